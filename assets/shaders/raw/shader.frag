@@ -1,15 +1,47 @@
 #version 450
 
-// @[internal_buffer]
-layout (set = 0, binding = 0) uniform PerViewData {
-    vec4 uniform_color;
-} uniform_data;
+// Sets 0 and 1 are the per view and per object data of shader.vert
+
+// Matches MaterialUniform in mesh_renderer_plugin
+// @[export]
+layout (set = 2, binding = 0) uniform MaterialData {
+    vec4 base_color;
+    vec4 emissive;
+    float metallic;
+    float roughness;
+    float reflectance;
+    uint unlit;
+} material_data;
+
+// Matches the TEXTURE_BINDINGS of StandardMaterial in mesh_renderer_plugin, white when a
+// material has no texture
+layout (set = 2, binding = 1) uniform texture2D base_color_texture;
+layout (set = 2, binding = 2) uniform sampler base_color_sampler;
+layout (set = 2, binding = 3) uniform texture2D metallic_roughness_texture;
+layout (set = 2, binding = 4) uniform sampler metallic_roughness_sampler;
+layout (set = 2, binding = 5) uniform texture2D normal_texture;
+layout (set = 2, binding = 6) uniform sampler normal_sampler;
+layout (set = 2, binding = 7) uniform texture2D occlusion_texture;
+layout (set = 2, binding = 8) uniform sampler occlusion_sampler;
+layout (set = 2, binding = 9) uniform texture2D emissive_texture;
+layout (set = 2, binding = 10) uniform sampler emissive_sampler;
 
 layout (location = 0) in vec4 in_color;
+layout (location = 1) in vec2 in_tex_coord;
 
 layout (location = 0) out vec4 out_color;
 
 void main() {
-    //out_color = in_color;
-    out_color = uniform_data.uniform_color;
+    vec4 base_color = material_data.base_color * in_color
+        * texture(sampler2D(base_color_texture, base_color_sampler), in_tex_coord);
+    if (material_data.unlit != 0) {
+        out_color = base_color;
+        return;
+    }
+
+    // There is no lighting yet, the metallic roughness and normal textures are bound for it
+    float occlusion = texture(sampler2D(occlusion_texture, occlusion_sampler), in_tex_coord).r;
+    vec3 emissive = material_data.emissive.rgb
+        * texture(sampler2D(emissive_texture, emissive_sampler), in_tex_coord).rgb;
+    out_color = vec4(base_color.rgb * occlusion + emissive, base_color.a);
 }
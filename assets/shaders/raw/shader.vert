@@ -5,12 +5,28 @@
 
 // @[semantic("POSITION")]
 layout (location = 0) in vec4 pos;
+// @[semantic("TEXCOORD")]
+layout (location = 1) in vec2 in_tex_coord;
 // @[semantic("COLOR")]
-layout (location = 1) in vec4 in_color;
+layout (location = 2) in vec4 in_color;
+
+// Matches PerViewUniform in mesh_renderer_plugin
+// @[internal_buffer]
+layout (set = 0, binding = 0) uniform PerViewData {
+    mat4 view_proj;
+} per_view_data;
+
+// Matches PerObjectUniform in mesh_renderer_plugin
+// @[internal_buffer]
+layout (set = 1, binding = 0) uniform PerObjectData {
+    mat4 model;
+} per_object_data;
 
 layout (location = 0) out vec4 out_color;
+layout (location = 1) out vec2 out_tex_coord;
 
 void main() {
     out_color = in_color;
-    gl_Position = pos;
+    out_tex_coord = in_tex_coord;
+    gl_Position = per_view_data.view_proj * per_object_data.model * vec4(pos.xyz, 1.0);
 }
@@ -0,0 +1,174 @@
+//! Keeps the cooked shaders in sync with the GLSL in `assets/shaders/raw`. With the
+//! `cook_shaders` feature every raw shader is compiled to SPIR-V and MSL in
+//! `assets/shaders/processed` and packaged in `assets/shaders/cooked`. Without it, the build fails
+//! when a raw shader changed since it was last cooked.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const RAW_SHADERS_DIR: &str = "assets/shaders/raw";
+const PROCESSED_SHADERS_DIR: &str = "assets/shaders/processed";
+const COOKED_SHADERS_DIR: &str = "assets/shaders/cooked";
+/// Hashes of the raw shaders the cooked shaders were cooked from, one `<file name> <hash>` per line
+const RAW_SHADER_HASHES_FILE: &str = "assets/shaders/cooked/raw_shaders.hashes";
+
+const SHADER_EXTENSIONS: &[&str] = &["vert", "frag", "comp"];
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", RAW_SHADERS_DIR);
+    println!("cargo:rerun-if-changed={}", PROCESSED_SHADERS_DIR);
+    println!("cargo:rerun-if-changed={}", COOKED_SHADERS_DIR);
+
+    let raw_shaders = raw_shaders();
+
+    #[cfg(feature = "cook_shaders")]
+    cook::cook_shaders(&raw_shaders);
+
+    let stale_shaders = stale_shaders(&raw_shaders);
+    if !stale_shaders.is_empty() {
+        panic!(
+            "The cooked shaders are stale:\n{}\nCook them again with `cargo build --features cook_shaders`",
+            stale_shaders.join("\n")
+        );
+    }
+}
+
+/// File names of the shaders in `RAW_SHADERS_DIR`, sorted
+fn raw_shaders() -> Vec<String> {
+    let mut raw_shaders = fs::read_dir(RAW_SHADERS_DIR)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", RAW_SHADERS_DIR, err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .map_or(false, |extension| SHADER_EXTENSIONS.contains(&extension))
+        })
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    raw_shaders.sort();
+    raw_shaders
+}
+
+/// Every file cooked from a raw shader
+fn cooked_files(raw_shader: &str) -> [PathBuf; 4] {
+    [
+        Path::new(PROCESSED_SHADERS_DIR).join(format!("{}.spv", raw_shader)),
+        Path::new(PROCESSED_SHADERS_DIR).join(format!("{}.metal", raw_shader)),
+        Path::new(COOKED_SHADERS_DIR).join(format!("{}.cookedshaderpackage", raw_shader)),
+        Path::new(COOKED_SHADERS_DIR).join(format!("{}.cookedshaderpackage.meta", raw_shader)),
+    ]
+}
+
+/// 64 bit FNV-1a of the shader source, which unlike `DefaultHasher` is stable across Rust
+/// versions. Carriage returns are skipped so checkouts with CRLF line endings hash the same.
+fn raw_shader_hash(raw_shader: &str) -> String {
+    let path = Path::new(RAW_SHADERS_DIR).join(raw_shader);
+    let source =
+        fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err));
+
+    let hash = source
+        .iter()
+        .filter(|&&byte| byte != b'\r')
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+fn read_raw_shader_hashes() -> BTreeMap<String, String> {
+    fs::read_to_string(RAW_SHADER_HASHES_FILE)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        })
+        .collect()
+}
+
+/// Describes every raw shader that was changed, added or removed since the last cook
+fn stale_shaders(raw_shaders: &[String]) -> Vec<String> {
+    let raw_shader_hashes = read_raw_shader_hashes();
+    let mut stale_shaders = Vec::new();
+
+    for raw_shader in raw_shaders {
+        match raw_shader_hashes.get(raw_shader) {
+            Some(hash) if *hash == raw_shader_hash(raw_shader) => {}
+            Some(_) => stale_shaders.push(format!("  {} changed", raw_shader)),
+            None => stale_shaders.push(format!("  {} was never cooked", raw_shader)),
+        }
+
+        for cooked_file in &cooked_files(raw_shader) {
+            if !cooked_file.exists() {
+                stale_shaders.push(format!("  {} is missing", cooked_file.display()));
+            }
+        }
+    }
+
+    for raw_shader in raw_shader_hashes.keys() {
+        if !raw_shaders.contains(raw_shader) {
+            stale_shaders.push(format!("  {} was removed", raw_shader));
+        }
+    }
+
+    stale_shaders
+}
+
+#[cfg(feature = "cook_shaders")]
+mod cook {
+    use std::{fs, path::Path};
+
+    use rafx_shader_processor::ShaderProcessorArgs;
+
+    use super::*;
+
+    pub fn cook_shaders(raw_shaders: &[String]) {
+        for raw_shader in raw_shaders {
+            let [spv_file, metal_file, cooked_file, meta_file] = cooked_files(raw_shader);
+
+            let args = ShaderProcessorArgs {
+                glsl_file: Some(Path::new(RAW_SHADERS_DIR).join(raw_shader)),
+                spv_file: Some(spv_file),
+                rs_file: None,
+                metal_generated_src_file: Some(metal_file),
+                cooked_shader_file: Some(cooked_file),
+                glsl_files: None,
+                spv_path: None,
+                rs_path: None,
+                metal_generated_src_path: None,
+                cooked_shaders_path: None,
+                shader_kind: None,
+                trace: false,
+                optimize_shaders: false,
+            };
+            if let Err(err) = rafx_shader_processor::run(&args) {
+                panic!("Failed to cook {}: {}", raw_shader, err);
+            }
+
+            // The importer keeps assets by the UUID in their .meta file, so existing ones are kept
+            if !meta_file.exists() {
+                fs::write(&meta_file, cooked_shader_meta(uuid::Uuid::new_v4())).unwrap_or_else(
+                    |err| panic!("Failed to write {}: {}", meta_file.display(), err),
+                );
+            }
+        }
+
+        let raw_shader_hashes = raw_shaders
+            .iter()
+            .map(|raw_shader| format!("{} {}\n", raw_shader, raw_shader_hash(raw_shader)))
+            .collect::<String>();
+        fs::write(RAW_SHADER_HASHES_FILE, raw_shader_hashes)
+            .unwrap_or_else(|err| panic!("Failed to write {}: {}", RAW_SHADER_HASHES_FILE, err));
+    }
+
+    /// The .meta file the asset pipeline imports a `.cookedshaderpackage` with
+    fn cooked_shader_meta(uuid: uuid::Uuid) -> String {
+        format!(
+            "(\n    version: 2,\n    importer_options: (),\n    importer_state: (Some(\"{}\")),\n)",
+            uuid
+        )
+    }
+}
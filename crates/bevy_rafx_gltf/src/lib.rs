@@ -8,6 +8,7 @@ use bevy::asset::{AddAsset, Handle};
 use bevy::reflect::TypeUuid;
 use bevy::scene::Scene;
 
+use bevy_rafx_plugin::{Camera, OrthographicProjection, PerspectiveProjection};
 use mesh_renderer_plugin::Mesh;
 use mesh_renderer_plugin::StandardMaterial;
 
@@ -21,7 +22,11 @@ impl Plugin for GltfPlugin {
             .add_asset::<Gltf>()
             .add_asset::<GltfNode>()
             .add_asset::<GltfPrimitive>()
-            .add_asset::<GltfMesh>();
+            .add_asset::<GltfMesh>()
+            // Scenes can contain cameras, which have to be registered to be spawned
+            .register_type::<Camera>()
+            .register_type::<PerspectiveProjection>()
+            .register_type::<OrthographicProjection>();
     }
 }
 
@@ -12,15 +12,17 @@ use bevy::transform::{
     prelude::{GlobalTransform, Transform},
 };
 
+use bevy_rafx_plugin::{Camera, CameraProjection, OrthographicProjection, PerspectiveProjection};
 use mesh_renderer_plugin::{
     mesh::{Indices, Mesh, VertexAttributeValues},
     texture::{
         AddressMode, FilterMode, ImageType, SamplerDescriptor, Texture, TextureError, TextureFormat,
     },
-    Color, PbrBundle, PrimitiveTopology, StandardMaterial,
+    AlphaBlend, Color, MeshAabb, PbrBundle, PrimitiveTopology, StandardMaterial,
 };
 
 use gltf::{
+    material::AlphaMode,
     mesh::Mode,
     texture::{MagFilter, MinFilter, WrappingMode},
     Material, Primitive,
@@ -448,53 +450,54 @@ fn load_node(
     }
 
     // create camera node
-    // if let Some(camera) = gltf_node.camera() {
-    //     node.insert(VisibleEntities {
-    //         ..Default::default()
-    //     });
-
-    //     match camera.projection() {
-    //         gltf::camera::Projection::Orthographic(orthographic) => {
-    //             let xmag = orthographic.xmag();
-    //             let ymag = orthographic.ymag();
-    //             let orthographic_projection: OrthographicProjection = OrthographicProjection {
-    //                 left: -xmag,
-    //                 right: xmag,
-    //                 top: ymag,
-    //                 bottom: -ymag,
-    //                 far: orthographic.zfar(),
-    //                 near: orthographic.znear(),
-    //                 ..Default::default()
-    //             };
-
-    //             node.insert(Camera {
-    //                 name: Some(base::camera::CAMERA_2D.to_owned()),
-    //                 projection_matrix: orthographic_projection.get_projection_matrix(),
-    //                 ..Default::default()
-    //             });
-    //             node.insert(orthographic_projection);
-    //         }
-    //         gltf::camera::Projection::Perspective(perspective) => {
-    //             let mut perspective_projection: PerspectiveProjection = PerspectiveProjection {
-    //                 fov: perspective.yfov(),
-    //                 near: perspective.znear(),
-    //                 ..Default::default()
-    //             };
-    //             if let Some(zfar) = perspective.zfar() {
-    //                 perspective_projection.far = zfar;
-    //             }
-    //             if let Some(aspect_ratio) = perspective.aspect_ratio() {
-    //                 perspective_projection.aspect_ratio = aspect_ratio;
-    //             }
-    //             node.insert(Camera {
-    //                 name: Some(base::camera::CAMERA_3D.to_owned()),
-    //                 projection_matrix: perspective_projection.get_projection_matrix(),
-    //                 ..Default::default()
-    //             });
-    //             node.insert(perspective_projection);
-    //         }
-    //     }
-    // }
+    if let Some(camera) = gltf_node.camera() {
+        let name = camera
+            .name()
+            .or_else(|| gltf_node.name())
+            .map(str::to_string);
+
+        match camera.projection() {
+            gltf::camera::Projection::Orthographic(orthographic) => {
+                let xmag = orthographic.xmag();
+                let ymag = orthographic.ymag();
+                let orthographic_projection: OrthographicProjection = OrthographicProjection {
+                    left: -xmag,
+                    right: xmag,
+                    top: ymag,
+                    bottom: -ymag,
+                    far: orthographic.zfar(),
+                    near: orthographic.znear(),
+                    ..Default::default()
+                };
+
+                node.insert(Camera {
+                    name,
+                    projection_matrix: orthographic_projection.get_projection_matrix(),
+                    ..Default::default()
+                });
+                node.insert(orthographic_projection);
+            }
+            gltf::camera::Projection::Perspective(perspective) => {
+                let mut perspective_projection: PerspectiveProjection = PerspectiveProjection {
+                    fov: perspective.yfov(),
+                    near: perspective.znear(),
+                    ..Default::default()
+                };
+                if let Some(zfar) = perspective.zfar() {
+                    perspective_projection.far = zfar;
+                }
+                if let Some(aspect_ratio) = perspective.aspect_ratio() {
+                    perspective_projection.aspect_ratio = aspect_ratio;
+                }
+                node.insert(Camera {
+                    name,
+                    projection_matrix: perspective_projection.get_projection_matrix(),
+                    ..Default::default()
+                });
+                node.insert(perspective_projection);
+            }
+        }
+    }
 
     node.with_children(|parent| {
         if let Some(mesh) = gltf_node.mesh() {
@@ -516,11 +519,21 @@ fn load_node(
                 let material_asset_path =
                     AssetPath::new_ref(load_context.path(), Some(&material_label));
 
-                parent.spawn_bundle(PbrBundle {
+                let mut primitive_entity = parent.spawn_bundle(PbrBundle {
                     mesh: load_context.get_handle(mesh_asset_path),
                     material: load_context.get_handle(material_asset_path),
                     ..Default::default()
                 });
+                if material.alpha_mode() == AlphaMode::Blend {
+                    primitive_entity.insert(AlphaBlend);
+                }
+
+                // glTF requires min and max on position accessors
+                let bounding_box = primitive.bounding_box();
+                primitive_entity.insert(MeshAabb {
+                    min: bevy::math::Vec3::from(bounding_box.min),
+                    max: bevy::math::Vec3::from(bounding_box.max),
+                });
             }
         }
 
@@ -1,11 +1,11 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 
 use bevy::{
-    ecs::reflect::ReflectComponent,
+    ecs::{bundle::Bundle, reflect::ReflectComponent},
     math::Vec3,
     prelude::{
-        CoreStage, GlobalTransform, IntoSystem, Plugin, Query, Res, ResMut, StageLabel,
-        StartupStage, SystemStage, Transform,
+        CoreStage, Entity, GlobalTransform, IntoSystem, Or, Plugin, Query, RemovedComponents, Res,
+        ResMut, StageLabel, StartupStage, SystemStage, Transform, With,
     },
     reflect::Reflect,
     window::Windows,
@@ -14,17 +14,34 @@ pub use bevy_render::{
     camera::{Camera, CameraProjection, OrthographicProjection, PerspectiveProjection},
     entity::PerspectiveCameraBundle,
 };
-pub use rafx::nodes::{RenderFeatureMask, RenderFeatureMaskBuilder};
+pub use rafx::nodes::{
+    RenderFeatureMask, RenderFeatureMaskBuilder, RenderPhaseMask, RenderPhaseMaskBuilder,
+};
 use rafx::{
     nodes::{
-        FramePacket, FramePacketBuilder, RenderPhaseMask, RenderPhaseMaskBuilder, RenderRegistry,
-        RenderRegistryBuilder, RenderViewDepthRange, RenderViewSet,
+        FramePacket, FramePacketBuilder, RenderRegistry, RenderView, RenderViewDepthRange,
+        RenderViewSet,
     },
-    rafx_visibility::{DepthRange, PerspectiveParameters, Projection},
+    rafx_visibility::{DepthRange, OrthographicParameters, PerspectiveParameters, Projection},
     visibility::{VisibilityObjectArc, VisibilityRegion},
 };
 
 pub mod phases;
+mod registry;
+pub use registry::{RegisteredRenderFeatures, RegisteredRenderPhases, RenderRegistryExt};
+mod material_pass;
+pub use material_pass::{create_material_pass, load_cooked_shader_package, COOKED_SHADERS_DIR};
+mod render_resources;
+pub use render_resources::RenderResources;
+mod submit;
+pub use submit::{
+    PreparedRenderFeature, PreparedRenderFeatures, Renderer, DEPTH_FORMAT, OFFSCREEN_COLOR_FORMAT,
+};
+#[cfg(any(test, feature = "test_util"))]
+pub mod test_util;
+
+#[cfg(not(any(feature = "vulkan", feature = "metal", feature = "empty")))]
+compile_error!("bevy_rafx_plugin needs at least one of the `vulkan`, `metal` or `empty` features");
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
 pub enum RenderStage {
@@ -38,18 +55,120 @@ pub enum RenderStage {
 #[derive(Default)]
 pub struct BevyRafxPlugin;
 
+/// A `PerspectiveCameraBundle` that renders every registered render feature and phase. Insert a
+/// `RenderFeatureMask` or `RenderPhaseMask` to render less.
+#[derive(Bundle)]
+pub struct RafxCameraBundle {
+    #[bundle]
+    pub perspective_camera_bundle: PerspectiveCameraBundle,
+}
+
+impl RafxCameraBundle {
+    pub fn new_3d() -> Self {
+        RafxCameraBundle {
+            perspective_camera_bundle: PerspectiveCameraBundle::new_3d(),
+        }
+    }
+
+    pub fn with_name(name: &str) -> Self {
+        RafxCameraBundle {
+            perspective_camera_bundle: PerspectiveCameraBundle::with_name(name),
+        }
+    }
+}
+
+impl Default for RafxCameraBundle {
+    fn default() -> Self {
+        Self::new_3d()
+    }
+}
+
+/// The rafx backend to render with. Insert this resource before adding `BevyRafxPlugin` to pick
+/// one, otherwise the first enabled backend is used. The matching cargo feature must be enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RafxBackend {
+    Vulkan,
+    Metal,
+    Empty,
+}
+
+impl RafxBackend {
+    pub fn feature_name(&self) -> &'static str {
+        match self {
+            RafxBackend::Vulkan => "vulkan",
+            RafxBackend::Metal => "metal",
+            RafxBackend::Empty => "empty",
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            RafxBackend::Vulkan => cfg!(feature = "vulkan"),
+            RafxBackend::Metal => cfg!(feature = "metal"),
+            RafxBackend::Empty => cfg!(feature = "empty"),
+        }
+    }
+}
+
+impl Default for RafxBackend {
+    fn default() -> Self {
+        if cfg!(feature = "vulkan") {
+            RafxBackend::Vulkan
+        } else if cfg!(feature = "metal") {
+            RafxBackend::Metal
+        } else {
+            RafxBackend::Empty
+        }
+    }
+}
+
+/// Insert this resource to render without a window, e.g. on CI with `MinimalPlugins`.
+/// Views take their extents from here instead of from the camera's window, and the device is
+/// created on the `empty` backend, which needs the `empty` feature.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadlessRenderSettings {
+    pub extents: (u32, u32),
+}
+
+/// Views created this frame, which go into the next `FramePacket`
+#[derive(Default)]
+pub struct RenderViews(pub Vec<RenderView>);
+
+/// Views that the current `FramePacket` was built from, in creation order
+#[derive(Default)]
+pub struct FramePacketViews(pub Vec<RenderView>);
+
 impl Plugin for BevyRafxPlugin {
     fn build(&self, app: &mut bevy::prelude::AppBuilder) {
-        app
-            // Improve ergonomics of Options, maybe replace_with?
-            // Never actually None, but used to .take() and .replace() builder
-            .insert_resource::<Option<RenderRegistryBuilder>>(
-                Some(RenderRegistryBuilder::default()),
-            )
+        let backend = app
+            .world()
+            .get_resource::<RafxBackend>()
+            .copied()
+            .unwrap_or_default();
+        if !backend.is_enabled() {
+            panic!(
+                "RafxBackend::{:?} was requested, but bevy_rafx_plugin was built without the `{}` feature",
+                backend,
+                backend.feature_name()
+            );
+        }
+
+        app.insert_resource(backend)
             .insert_resource::<Option<RenderRegistry>>(None)
+            .init_resource::<RegisteredRenderFeatures>()
+            .init_resource::<RegisteredRenderPhases>()
+            .add_render_phase::<phases::depth_prepass_render_phase::DepthPrepassRenderPhase>(
+                "DepthPrepass",
+            )
+            .add_render_phase::<phases::opaque_render_phase::OpaqueRenderPhase>("Opaque")
+            .add_render_phase::<phases::transparent_render_phase::TransparentRenderPhase>(
+                "Transparent",
+            )
             .insert_resource(FramePacketBuilder::new())
             .insert_resource::<Option<FramePacket>>(None)
             .insert_resource(RenderViewSet::default())
+            .init_resource::<RenderViews>()
+            .init_resource::<FramePacketViews>()
             .insert_resource(VisibilityRegion::new())
             .add_stage_after(
                 CoreStage::PostUpdate,
@@ -77,90 +196,158 @@ impl Plugin for BevyRafxPlugin {
                 SystemStage::parallel(),
             )
             .add_startup_system_to_stage(StartupStage::PostStartup, build_render_registry.system())
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                render_resources::create_render_resources.system(),
+            )
+            .init_resource::<VisibilityObjects>()
+            .add_system_to_stage(RenderStage::Visibility, release_visibility_objects.system())
             .add_system_to_stage(RenderStage::PreExtract, build_frame_packet.system())
-            .add_system_to_stage(RenderStage::Extract, create_main_view.system());
+            .add_system_to_stage(RenderStage::Extract, create_views.system())
+            .init_resource::<PreparedRenderFeatures>()
+            .add_system_to_stage(RenderStage::Submit, submit::submit.system());
     }
 }
 
 fn build_render_registry(
-    mut render_registry_builder: ResMut<Option<RenderRegistryBuilder>>,
+    registered_render_features: Res<RegisteredRenderFeatures>,
+    registered_render_phases: Res<RegisteredRenderPhases>,
     mut render_registry: ResMut<Option<RenderRegistry>>,
 ) {
-    let render_registry_builder =
-        render_registry_builder
-            .take()
-            .unwrap()
-            .register_render_phase::<phases::opaque_render_phase::OpaqueRenderPhase>("Opaque");
-    render_registry.replace(render_registry_builder.build());
+    render_registry.replace(registry::build_render_registry(
+        &registered_render_features,
+        &registered_render_phases,
+    ));
 }
 
-fn create_main_view(
-    windows: Res<Windows>,
+fn create_views(
+    windows: Option<Res<Windows>>,
+    headless_render_settings: Option<Res<HeadlessRenderSettings>>,
     render_view_set_resource: ResMut<RenderViewSet>,
+    mut render_views_resource: ResMut<RenderViews>,
     visibility_region: Res<VisibilityRegion>,
     mut frame_packet_builder_resource: ResMut<FramePacketBuilder>,
-    // TODO different projections
-    query: Query<(
-        &Camera,
-        &PerspectiveProjection,
-        &GlobalTransform,
-        &RenderFeatureMask,
-    )>,
+    registered_render_features: Res<RegisteredRenderFeatures>,
+    registered_render_phases: Res<RegisteredRenderPhases>,
+    query: Query<
+        (
+            Entity,
+            &Camera,
+            Option<&PerspectiveProjection>,
+            Option<&OrthographicProjection>,
+            &GlobalTransform,
+            Option<&RenderFeatureMask>,
+            Option<&RenderPhaseMask>,
+        ),
+        Or<(With<PerspectiveProjection>, With<OrthographicProjection>)>,
+    >,
 ) {
-    // TODO multiple cameras
-    let window = windows.get_primary().unwrap();
-    let extents = (window.physical_width(), window.physical_height());
+    let default_render_feature_mask = registered_render_features.render_feature_mask();
+    let default_render_phase_mask = registered_render_phases.render_phase_mask();
 
-    let render_phase_mask = RenderPhaseMaskBuilder::default()
-        .add_render_phase::<phases::opaque_render_phase::OpaqueRenderPhase>()
-        .build();
+    for (
+        entity,
+        camera,
+        perspective_projection,
+        orthographic_projection,
+        global_transform,
+        render_feature_mask,
+        render_phase_mask,
+    ) in query.iter()
+    {
+        let extents = match (&headless_render_settings, &windows) {
+            (Some(headless_render_settings), _) => headless_render_settings.extents,
+            // Cameras rendering to a window that doesn't exist (anymore) have nothing to render to
+            (None, Some(windows)) => match windows.get(camera.window) {
+                Some(window) => (window.physical_width(), window.physical_height()),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
 
-    let (camera, projection, global_transform, render_feature_mask) = query.single().unwrap();
+        let (projection, depth_range) =
+            view_projection(perspective_projection, orthographic_projection);
 
-    let depth_range = RenderViewDepthRange::new(projection.near, projection.far);
+        // Every camera gets its own frustum, so visibility is queried per view
+        let view_frustum = visibility_region.register_view_frustum();
 
-    let view_frustum = visibility_region.register_view_frustum();
+        // Bevy cameras look along their local -Z
+        let look_at = global_transform.translation + global_transform.rotation * -Vec3::Z;
+        let up = global_transform.rotation * Vec3::Y;
 
-    let projection = Projection::Perspective(PerspectiveParameters::new(
-        projection.fov,
-        projection.aspect_ratio,
-        projection.near,
-        projection.far,
-        DepthRange::Normal,
-    ));
+        view_frustum.set_projection(&projection).set_transform(
+            global_transform.translation,
+            look_at,
+            up,
+        );
 
-    let forward = global_transform.rotation * Vec3::Z;
-    let up = global_transform.rotation * Vec3::Y;
+        let view_name = camera
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Camera {:?}", entity));
 
-    view_frustum.set_projection(&projection).set_transform(
-        global_transform.translation,
-        // TODO need to keep the lookat and up or calculate from rotation
-        forward,
-        up,
-    );
+        let view = render_view_set_resource.create_view(
+            view_frustum,
+            global_transform.translation,
+            global_transform.compute_matrix().inverse(),
+            projection.as_rh_mat4(),
+            extents,
+            depth_range,
+            render_phase_mask
+                .cloned()
+                .unwrap_or(default_render_phase_mask),
+            render_feature_mask
+                .cloned()
+                .unwrap_or_else(|| default_render_feature_mask.clone()),
+            view_name,
+        );
 
-    let main_view = render_view_set_resource.create_view(
-        view_frustum,
-        global_transform.translation,
-        global_transform.compute_matrix(),
-        projection.as_rh_mat4(),
-        extents,
-        depth_range,
-        render_phase_mask,
-        render_feature_mask.clone(),
-        camera.name.clone().unwrap(),
-    );
+        frame_packet_builder_resource.query_visibility_and_add_results(&view, &visibility_region);
 
-    frame_packet_builder_resource.query_visibility_and_add_results(&main_view, &visibility_region);
+        render_views_resource.0.push(view);
+    }
+}
+
+/// Converts a bevy camera projection into the matching rafx projection and depth range.
+/// Perspective takes precedence if a camera has both projection components.
+fn view_projection(
+    perspective_projection: Option<&PerspectiveProjection>,
+    orthographic_projection: Option<&OrthographicProjection>,
+) -> (Projection, RenderViewDepthRange) {
+    match (perspective_projection, orthographic_projection) {
+        (Some(projection), _) => (
+            Projection::Perspective(PerspectiveParameters::new(
+                projection.fov,
+                projection.aspect_ratio,
+                projection.near,
+                projection.far,
+                DepthRange::Normal,
+            )),
+            RenderViewDepthRange::new(projection.near, projection.far),
+        ),
+        (None, Some(projection)) => (
+            // Same bounds as OrthographicProjection::get_projection_matrix
+            Projection::Orthographic(OrthographicParameters::new(
+                projection.left * projection.scale,
+                projection.right * projection.scale,
+                projection.bottom * projection.scale,
+                projection.top * projection.scale,
+                projection.near,
+                projection.far,
+                DepthRange::Normal,
+            )),
+            RenderViewDepthRange::new(projection.near, projection.far),
+        ),
+        (None, None) => unreachable!("Query is filtered on having either projection"),
+    }
 }
 
 fn build_frame_packet(
     mut frame_packet_resource: ResMut<Option<FramePacket>>,
     mut frame_packet_builder_resource: ResMut<FramePacketBuilder>,
     mut render_view_set_resource: ResMut<RenderViewSet>,
-    visibility_region: Res<VisibilityRegion>,
-    // TODO different projections
-    query: Query<(&Camera, &PerspectiveProjection, &GlobalTransform)>,
+    mut render_views_resource: ResMut<RenderViews>,
+    mut frame_packet_views_resource: ResMut<FramePacketViews>,
 ) {
     // Swap in the new frame_packet_builder for next frame
     let mut frame_packet_builder = FramePacketBuilder::new();
@@ -175,6 +362,60 @@ fn build_frame_packet(
     // make the render_view_set for the next Frame and swap
     let mut render_view_set = RenderViewSet::default();
     std::mem::swap(&mut *render_view_set_resource, &mut render_view_set);
+
+    // the views that were just built into the frame packet, start collecting for the next Frame
+    frame_packet_views_resource.0.clear();
+    std::mem::swap(
+        &mut frame_packet_views_resource.0,
+        &mut render_views_resource.0,
+    );
+}
+
+/// Owns the visibility object of every entity with a `VisibilityComponent`, so it is released
+/// from the `VisibilityRegion` in `RenderStage::Visibility` of the frame the component is removed
+/// or the entity despawned.
+#[derive(Default)]
+pub struct VisibilityObjects(HashMap<Entity, VisibilityObjectArc>);
+
+impl VisibilityObjects {
+    /// Returns the previous object of the entity, if it had one
+    pub fn insert(
+        &mut self,
+        entity: Entity,
+        handle: VisibilityObjectArc,
+    ) -> Option<VisibilityObjectArc> {
+        self.0.insert(entity, handle)
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&VisibilityObjectArc> {
+        self.0.get(&entity)
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<VisibilityObjectArc> {
+        self.0.remove(&entity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn release_visibility_objects(
+    removed_visibility_components: RemovedComponents<VisibilityComponent>,
+    mut visibility_objects: ResMut<VisibilityObjects>,
+    query: Query<(), With<VisibilityComponent>>,
+) {
+    for entity in removed_visibility_components.iter() {
+        // The component may have been removed and inserted again in the same frame
+        if query.get(entity).is_err() {
+            // Dropping the last VisibilityObjectArc removes the object from the VisibilityRegion
+            visibility_objects.remove(entity);
+        }
+    }
 }
 
 #[derive(Clone, Default, Reflect)]
@@ -184,6 +425,13 @@ pub struct VisibilityComponent {
     pub handle: Option<VisibilityObjectArc>,
 }
 
+/// Marks an entity that never moves, so its visibility object is registered as a static object
+/// of the `VisibilityRegion`. Static entities whose `GlobalTransform` changes anyway are moved to
+/// a dynamic object, with a warning.
+#[derive(Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct StaticVisibility;
+
 impl Debug for VisibilityComponent {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         fmt.debug_struct("VisibilityComponent")
@@ -197,3 +445,188 @@ impl Debug for VisibilityComponent {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{headless_app, headless_app_with};
+
+    #[test]
+    fn headless_frame_packet() {
+        let mut app = headless_app();
+
+        app.world
+            .spawn()
+            .insert_bundle(PerspectiveCameraBundle::with_name("Perspective"))
+            .insert(RenderFeatureMaskBuilder::default().build());
+        app.world
+            .spawn()
+            .insert_bundle(PerspectiveCameraBundle::new_3d())
+            .insert(RenderFeatureMaskBuilder::default().build());
+
+        // Views are created in the first frame and built into the frame packet in the second
+        app.update();
+        app.update();
+
+        assert!(app
+            .world
+            .get_resource::<Option<FramePacket>>()
+            .unwrap()
+            .is_some());
+
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        assert_eq!(frame_packet_views.0.len(), 2);
+        assert!(frame_packet_views
+            .0
+            .iter()
+            .all(|view| view.extents() == (800, 600)));
+    }
+
+    mod test_render_feature {
+        use rafx::render_feature_mod_prelude::*;
+        rafx::declare_render_feature!(TestRenderFeature, TEST_FEATURE_INDEX);
+    }
+    use test_render_feature::TestRenderFeature;
+
+    #[test]
+    fn headless_default_masks() {
+        let mut app = headless_app_with(|app_builder| {
+            app_builder.add_render_feature::<TestRenderFeature>();
+        });
+
+        // Without masks, cameras render every registered feature and phase
+        app.world
+            .spawn()
+            .insert_bundle(PerspectiveCameraBundle::new_3d());
+
+        app.update();
+        app.update();
+
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        assert_eq!(frame_packet_views.0.len(), 1);
+        let view = &frame_packet_views.0[0];
+        assert!(view.feature_is_relevant::<TestRenderFeature>());
+        assert!(
+            view.phase_is_relevant::<phases::depth_prepass_render_phase::DepthPrepassRenderPhase>()
+        );
+        assert!(view.phase_is_relevant::<phases::opaque_render_phase::OpaqueRenderPhase>());
+        assert!(
+            view.phase_is_relevant::<phases::transparent_render_phase::TransparentRenderPhase>()
+        );
+        assert_eq!(
+            view.render_phase_mask(),
+            app.world
+                .get_resource::<RegisteredRenderPhases>()
+                .unwrap()
+                .render_phase_mask()
+        );
+        assert!(app
+            .world
+            .get_resource::<Option<RenderRegistry>>()
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "empty")]
+    fn headless_render_resources() {
+        let mut app = headless_app();
+
+        app.update();
+
+        assert!(app.world.get_resource::<RenderResources>().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "empty")]
+    fn headless_views_are_rendered_offscreen() {
+        let mut app = headless_app();
+
+        app.world.spawn().insert_bundle(RafxCameraBundle::default());
+
+        // Every frame is rendered, the view is created in the first frame and rendered in the second
+        app.update();
+        app.update();
+
+        let renderer = app.world.get_resource::<Renderer>().unwrap();
+        assert_eq!(renderer.frame_count(), 2);
+        let color_texture = renderer.offscreen_color_texture(0).unwrap();
+        assert_eq!(color_texture.texture_def().extents.width, 800);
+        assert_eq!(color_texture.texture_def().extents.height, 600);
+        assert!(renderer.offscreen_color_texture(1).is_none());
+        assert!(app
+            .world
+            .get_resource::<PreparedRenderFeatures>()
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn rafx_camera_bundle_renders_every_registered_feature() {
+        let mut app = headless_app_with(|app_builder| {
+            app_builder.add_render_feature::<TestRenderFeature>();
+        });
+
+        app.world.spawn().insert_bundle(RafxCameraBundle::default());
+
+        app.update();
+        app.update();
+
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        assert_eq!(frame_packet_views.0.len(), 1);
+        assert!(frame_packet_views.0[0].feature_is_relevant::<TestRenderFeature>());
+    }
+
+    #[test]
+    fn headless_explicit_render_phase_mask() {
+        let mut app = headless_app();
+
+        let opaque_only = RenderPhaseMaskBuilder::default()
+            .add_render_phase::<phases::opaque_render_phase::OpaqueRenderPhase>()
+            .build();
+        app.world
+            .spawn()
+            .insert_bundle(PerspectiveCameraBundle::new_3d())
+            .insert(opaque_only);
+
+        app.update();
+        app.update();
+
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        assert_eq!(frame_packet_views.0.len(), 1);
+        let render_phase_mask = frame_packet_views.0[0].render_phase_mask();
+        assert_eq!(render_phase_mask, opaque_only);
+        assert_ne!(
+            render_phase_mask,
+            app.world
+                .get_resource::<RegisteredRenderPhases>()
+                .unwrap()
+                .render_phase_mask()
+        );
+    }
+
+    #[test]
+    fn headless_orthographic_view() {
+        let mut app = headless_app();
+
+        app.world
+            .spawn()
+            .insert(Camera::default())
+            .insert(OrthographicProjection::default())
+            .insert(GlobalTransform::identity())
+            .insert(RenderFeatureMaskBuilder::default().build());
+        // Cameras without a projection don't produce a view
+        app.world
+            .spawn()
+            .insert(Camera::default())
+            .insert(GlobalTransform::identity())
+            .insert(RenderFeatureMaskBuilder::default().build());
+
+        app.update();
+        app.update();
+
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        assert_eq!(frame_packet_views.0.len(), 1);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{path::Path, sync::Arc};
+
+use rafx::{
+    api::{RafxError, RafxResult},
+    framework::{CookedShaderPackage, FixedFunctionState, MaterialPass, ReflectedEntryPoint},
+};
+
+use crate::RenderResources;
+
+/// Cooked shader packages of `assets/shaders/raw`, relative to the working directory
+pub const COOKED_SHADERS_DIR: &str = "assets/shaders/cooked";
+
+/// Reads a `.cookedshaderpackage` from `COOKED_SHADERS_DIR`, e.g. `"shader.vert.cookedshaderpackage"`
+pub fn load_cooked_shader_package(name: &str) -> RafxResult<CookedShaderPackage> {
+    let path = Path::new(COOKED_SHADERS_DIR).join(name);
+    let bytes = std::fs::read(&path).map_err(|err| {
+        RafxError::StringError(format!("Failed to read {}: {}", path.display(), err))
+    })?;
+    bincode::deserialize(&bytes).map_err(|err| {
+        RafxError::StringError(format!("Failed to deserialize {}: {}", path.display(), err))
+    })
+}
+
+/// Creates a material pass from cooked shader packages, one per stage, using their `main` entry
+/// points
+pub fn create_material_pass(
+    render_resources: &RenderResources,
+    debug_name: &str,
+    shader_packages: &[&str],
+    fixed_function_state: FixedFunctionState,
+) -> RafxResult<MaterialPass> {
+    let resources = render_resources.resource_manager.resources();
+
+    let mut shader_modules = Vec::with_capacity(shader_packages.len());
+    let mut entry_points = Vec::with_capacity(shader_packages.len());
+    for &name in shader_packages {
+        let cooked_shader_package = load_cooked_shader_package(name)?;
+        let entry_point: ReflectedEntryPoint = cooked_shader_package
+            .find_entry_point("main")
+            .cloned()
+            .ok_or_else(|| RafxError::StringError(format!("{} has no `main` entry point", name)))?;
+
+        shader_modules.push(
+            resources.get_or_create_shader_module_from_cooked_package(&cooked_shader_package)?,
+        );
+        entry_points.push(entry_point);
+    }
+
+    let entry_points = entry_points.iter().collect::<Vec<_>>();
+    MaterialPass::new(
+        &render_resources.resource_manager.resource_context(),
+        Some(debug_name),
+        Arc::new(fixed_function_state),
+        shader_modules,
+        &entry_points,
+    )
+}
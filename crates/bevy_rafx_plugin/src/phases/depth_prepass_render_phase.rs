@@ -0,0 +1,38 @@
+use rafx::nodes::RenderPhase;
+use rafx::nodes::{RenderPhaseIndex, SubmitNode};
+
+rafx::declare_render_phase!(
+    DepthPrepassRenderPhase,
+    DEPTH_PREPASS_RENDER_PHASE_INDEX,
+    depth_prepass_render_phase_sort_submit_nodes
+);
+
+fn depth_prepass_render_phase_sort_submit_nodes(
+    mut submit_nodes: Vec<SubmitNode>,
+) -> Vec<SubmitNode> {
+    // Front-to-back, so occluded fragments fail the depth test early
+    submit_nodes.sort_unstable_by(|a, b| {
+        a.distance()
+            .partial_cmp(&b.distance())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    submit_nodes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{submit_node_ids, submit_nodes_at};
+
+    #[test]
+    fn sorts_front_to_back() {
+        let submit_nodes = submit_nodes_at::<DepthPrepassRenderPhase>(&[2.0, 10.0, 0.5, 5.0]);
+
+        let sorted = DepthPrepassRenderPhase::sort_submit_nodes(submit_nodes);
+
+        assert_eq!(submit_node_ids(&sorted), vec![2, 0, 3, 1]);
+        assert!(sorted
+            .windows(2)
+            .all(|pair| pair[0].distance() <= pair[1].distance()));
+    }
+}
@@ -1 +1,3 @@
+pub mod depth_prepass_render_phase;
 pub mod opaque_render_phase;
+pub mod transparent_render_phase;
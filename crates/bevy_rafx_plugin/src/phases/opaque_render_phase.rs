@@ -8,6 +8,6 @@ rafx::declare_render_phase!(
 );
 
 fn opaque_render_phase_sort_submit_nodes(submit_nodes: Vec<SubmitNode>) -> Vec<SubmitNode> {
-    // Sorting is unnecessary because of the depth pre-pass.
+    // Sorting is unnecessary because of the depth pre-pass in DepthPrepassRenderPhase.
     submit_nodes
 }
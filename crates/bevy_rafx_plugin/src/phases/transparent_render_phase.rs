@@ -0,0 +1,38 @@
+use rafx::nodes::RenderPhase;
+use rafx::nodes::{RenderPhaseIndex, SubmitNode};
+
+rafx::declare_render_phase!(
+    TransparentRenderPhase,
+    TRANSPARENT_RENDER_PHASE_INDEX,
+    transparent_render_phase_sort_submit_nodes
+);
+
+fn transparent_render_phase_sort_submit_nodes(
+    mut submit_nodes: Vec<SubmitNode>,
+) -> Vec<SubmitNode> {
+    // Blending only composites correctly when drawing back-to-front
+    submit_nodes.sort_unstable_by(|a, b| {
+        b.distance()
+            .partial_cmp(&a.distance())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    submit_nodes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{submit_node_ids, submit_nodes_at};
+
+    #[test]
+    fn sorts_back_to_front() {
+        let submit_nodes = submit_nodes_at::<TransparentRenderPhase>(&[2.0, 10.0, 0.5, 5.0]);
+
+        let sorted = TransparentRenderPhase::sort_submit_nodes(submit_nodes);
+
+        assert_eq!(submit_node_ids(&sorted), vec![1, 3, 0, 2]);
+        assert!(sorted
+            .windows(2)
+            .all(|pair| pair[0].distance() >= pair[1].distance()));
+    }
+}
@@ -0,0 +1,167 @@
+use bevy::{ecs::world::World, prelude::AppBuilder};
+use rafx::nodes::{
+    RenderFeature, RenderFeatureMask, RenderFeatureMaskBuilder, RenderPhase, RenderPhaseIndex,
+    RenderPhaseMask, RenderPhaseMaskBuilder, RenderRegistry, RenderRegistryBuilder,
+};
+
+struct RegisteredRenderFeature {
+    register: fn(RenderRegistryBuilder) -> RenderRegistryBuilder,
+    add_to_mask: fn(RenderFeatureMaskBuilder) -> RenderFeatureMaskBuilder,
+}
+
+/// All render features registered through `RenderRegistryExt`. The `RenderRegistry` is built
+/// from them, so cameras without a `RenderFeatureMask` component render exactly the features of
+/// the registry.
+#[derive(Default)]
+pub struct RegisteredRenderFeatures {
+    features: Vec<RegisteredRenderFeature>,
+}
+
+impl RegisteredRenderFeatures {
+    pub fn render_feature_mask(&self) -> RenderFeatureMask {
+        self.features
+            .iter()
+            .fold(RenderFeatureMaskBuilder::default(), |builder, feature| {
+                (feature.add_to_mask)(builder)
+            })
+            .build()
+    }
+}
+
+struct RegisteredRenderPhase {
+    name: String,
+    render_phase_index: fn() -> RenderPhaseIndex,
+    register: fn(RenderRegistryBuilder, &str) -> RenderRegistryBuilder,
+    add_to_mask: fn(RenderPhaseMaskBuilder) -> RenderPhaseMaskBuilder,
+}
+
+/// All render phases registered through `RenderRegistryExt`. The `RenderRegistry` is built from
+/// them, so cameras without a `RenderPhaseMask` component render exactly the phases of the
+/// registry.
+#[derive(Default)]
+pub struct RegisteredRenderPhases {
+    phases: Vec<RegisteredRenderPhase>,
+}
+
+impl RegisteredRenderPhases {
+    pub fn render_phase_mask(&self) -> RenderPhaseMask {
+        self.phases
+            .iter()
+            .fold(RenderPhaseMaskBuilder::default(), |builder, phase| {
+                (phase.add_to_mask)(builder)
+            })
+            .build()
+    }
+
+    /// Indices of the registered phases, in the order they are rendered in
+    pub fn render_phase_indices(&self) -> Vec<RenderPhaseIndex> {
+        self.phases
+            .iter()
+            .map(|phase| (phase.render_phase_index)())
+            .collect()
+    }
+}
+
+/// Builds the `RenderRegistry` from everything registered through `RenderRegistryExt`
+pub(crate) fn build_render_registry(
+    registered_render_features: &RegisteredRenderFeatures,
+    registered_render_phases: &RegisteredRenderPhases,
+) -> RenderRegistry {
+    let render_registry_builder = registered_render_features
+        .features
+        .iter()
+        .fold(RenderRegistryBuilder::default(), |builder, feature| {
+            (feature.register)(builder)
+        });
+    registered_render_phases
+        .phases
+        .iter()
+        .fold(render_registry_builder, |builder, phase| {
+            (phase.register)(builder, &phase.name)
+        })
+        .build()
+}
+
+/// Registers render features and phases while building the app. The `RenderRegistry` is built
+/// from them at `StartupStage::PostStartup`. Phases are rendered in the order they are
+/// registered in.
+pub trait RenderRegistryExt {
+    fn add_render_feature<F: RenderFeature>(&mut self) -> &mut Self;
+
+    fn add_render_phase<P: RenderPhase>(&mut self, name: &str) -> &mut Self;
+}
+
+impl RenderRegistryExt for AppBuilder {
+    fn add_render_feature<F: RenderFeature>(&mut self) -> &mut Self {
+        check_registration(self.world(), std::any::type_name::<F>());
+        self.world_mut()
+            .get_resource_mut::<RegisteredRenderFeatures>()
+            .unwrap()
+            .features
+            .push(RegisteredRenderFeature {
+                register: RenderRegistryBuilder::register_feature::<F>,
+                add_to_mask: RenderFeatureMaskBuilder::add_render_feature::<F>,
+            });
+        self
+    }
+
+    fn add_render_phase<P: RenderPhase>(&mut self, name: &str) -> &mut Self {
+        check_registration(self.world(), std::any::type_name::<P>());
+        self.world_mut()
+            .get_resource_mut::<RegisteredRenderPhases>()
+            .unwrap()
+            .phases
+            .push(RegisteredRenderPhase {
+                name: name.to_string(),
+                render_phase_index: P::render_phase_index,
+                register: RenderRegistryBuilder::register_render_phase::<P>,
+                add_to_mask: RenderPhaseMaskBuilder::add_render_phase::<P>,
+            });
+        self
+    }
+}
+
+fn check_registration(world: &World, registered_name: &str) {
+    let render_registry = world
+        .get_resource::<Option<RenderRegistry>>()
+        .unwrap_or_else(|| {
+            panic!(
+                "Cannot register {}, BevyRafxPlugin must be added first",
+                registered_name
+            )
+        });
+
+    // Anything registered after the RenderRegistry is built would be missing from it
+    if render_registry.is_some() {
+        panic!(
+            "Cannot register {}, the RenderRegistry has already been built",
+            registered_name
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RenderRegistryExt;
+    use crate::{phases::opaque_render_phase::OpaqueRenderPhase, BevyRafxPlugin};
+    use bevy::prelude::{App, MinimalPlugins};
+
+    #[test]
+    #[should_panic(expected = "BevyRafxPlugin must be added first")]
+    fn register_without_plugin() {
+        App::build().add_render_phase::<OpaqueRenderPhase>("Opaque");
+    }
+
+    #[test]
+    #[should_panic(expected = "the RenderRegistry has already been built")]
+    fn register_after_build() {
+        let mut app_builder = App::build();
+        app_builder
+            .add_plugins(MinimalPlugins)
+            .add_plugin(BevyRafxPlugin);
+        // Runs the startup stages, which build the RenderRegistry
+        app_builder.app.update();
+
+        app_builder.add_render_phase::<OpaqueRenderPhase>("Opaque");
+    }
+}
@@ -0,0 +1,157 @@
+use bevy::{
+    log::error,
+    prelude::{Commands, Local, Res},
+    window::Windows,
+    winit::WinitWindows,
+};
+#[cfg(not(feature = "empty"))]
+use rafx::api::RafxError;
+use rafx::{
+    api::{RafxApi, RafxApiDef, RafxDeviceContext, RafxResult},
+    framework::ResourceManager,
+    nodes::RenderRegistry,
+};
+use raw_window_handle::HasRawWindowHandle;
+#[cfg(feature = "empty")]
+use raw_window_handle::RawWindowHandle;
+
+use crate::{HeadlessRenderSettings, RafxBackend, Renderer};
+
+/// The rafx device and the resources created on it. Inserted once the primary window exists, or
+/// right away on the `empty` backend for headless apps, along with the `Renderer`.
+pub struct RenderResources {
+    // Dropped in declaration order, the device goes last
+    pub resource_manager: ResourceManager,
+    pub device_context: RafxDeviceContext,
+    pub api: RafxApi,
+}
+
+impl RenderResources {
+    pub fn new(
+        backend: RafxBackend,
+        window: &dyn HasRawWindowHandle,
+        render_registry: &RenderRegistry,
+    ) -> RafxResult<Self> {
+        let api_def = RafxApiDef::default();
+        #[allow(unreachable_patterns)]
+        let api = unsafe {
+            match backend {
+                #[cfg(feature = "vulkan")]
+                RafxBackend::Vulkan => RafxApi::new_vulkan(window, &api_def, &Default::default())?,
+                #[cfg(feature = "metal")]
+                RafxBackend::Metal => RafxApi::new_metal(window, &api_def, &Default::default())?,
+                #[cfg(feature = "empty")]
+                RafxBackend::Empty => RafxApi::new_empty(window, &api_def, &Default::default())?,
+                // Backends without their feature are rejected when the plugin is built
+                _ => unreachable!(),
+            }
+        };
+
+        let device_context = api.device_context();
+        let resource_manager = ResourceManager::new(&device_context, render_registry);
+
+        Ok(RenderResources {
+            resource_manager,
+            device_context,
+            api,
+        })
+    }
+}
+
+/// Stands in for the window of headless apps, the `empty` backend never uses the handle
+#[cfg(feature = "empty")]
+struct HeadlessWindow;
+
+#[cfg(feature = "empty")]
+unsafe impl HasRawWindowHandle for HeadlessWindow {
+    fn raw_window_handle(&self) -> RawWindowHandle {
+        #[cfg(target_os = "windows")]
+        return RawWindowHandle::Windows(raw_window_handle::windows::WindowsHandle::empty());
+        #[cfg(target_os = "macos")]
+        return RawWindowHandle::MacOS(raw_window_handle::macos::MacOSHandle::empty());
+        #[cfg(target_os = "ios")]
+        return RawWindowHandle::IOS(raw_window_handle::ios::IOSHandle::empty());
+        #[cfg(target_os = "android")]
+        return RawWindowHandle::Android(raw_window_handle::android::AndroidHandle::empty());
+        #[cfg(target_arch = "wasm32")]
+        return RawWindowHandle::Web(raw_window_handle::web::WebHandle::empty());
+        #[cfg(not(any(
+            target_os = "windows",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "android",
+            target_arch = "wasm32"
+        )))]
+        return RawWindowHandle::Xlib(raw_window_handle::unix::XlibHandle::empty());
+    }
+}
+
+/// Creates `RenderResources` and a `Renderer` for the primary window, once it exists. Headless apps
+/// get a device on the `empty` backend instead, which renders offscreen, so the whole pipeline
+/// runs without a GPU, e.g. on CI. Only tried once if it fails.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn create_render_resources(
+    mut commands: Commands,
+    mut failed: Local<bool>,
+    render_resources: Option<Res<RenderResources>>,
+    backend: Res<RafxBackend>,
+    render_registry: Res<Option<RenderRegistry>>,
+    headless_render_settings: Option<Res<HeadlessRenderSettings>>,
+    windows: Option<Res<Windows>>,
+    winit_windows: Option<Res<WinitWindows>>,
+) {
+    if render_resources.is_some() || *failed {
+        return;
+    }
+
+    let render_registry = match render_registry.as_ref() {
+        Some(render_registry) => render_registry,
+        None => return,
+    };
+
+    let (backend, result) = if headless_render_settings.is_some() {
+        #[cfg(feature = "empty")]
+        let result = RenderResources::new(RafxBackend::Empty, &HeadlessWindow, render_registry)
+            .and_then(|render_resources| {
+                let renderer = Renderer::new(&render_resources, None)?;
+                Ok((render_resources, renderer))
+            });
+        #[cfg(not(feature = "empty"))]
+        let result = Err(RafxError::StringError(
+            "headless rendering needs the `empty` feature".to_string(),
+        ));
+        (RafxBackend::Empty, result)
+    } else {
+        let (window, extents) = match (&windows, &winit_windows) {
+            (Some(windows), Some(winit_windows)) => match windows.get_primary().and_then(|window| {
+                let extents = (window.physical_width(), window.physical_height());
+                Some((winit_windows.get_window(window.id())?, extents))
+            }) {
+                Some(window) => window,
+                None => return,
+            },
+            _ => return,
+        };
+        (
+            *backend,
+            RenderResources::new(*backend, window, render_registry).and_then(|render_resources| {
+                let renderer = Renderer::new(
+                    &render_resources,
+                    Some((window as &dyn HasRawWindowHandle, extents)),
+                )?;
+                Ok((render_resources, renderer))
+            }),
+        )
+    };
+
+    match result {
+        Ok((render_resources, renderer)) => {
+            commands.insert_resource(render_resources);
+            commands.insert_resource(renderer);
+        }
+        Err(err) => {
+            error!("Failed to create the rafx {:?} device: {:?}", backend, err);
+            *failed = true;
+        }
+    }
+}
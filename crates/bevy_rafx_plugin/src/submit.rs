@@ -0,0 +1,393 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::{
+    log::error,
+    prelude::{Res, ResMut},
+    window::Windows,
+};
+use rafx::{
+    api::{
+        RafxColorClearValue, RafxColorRenderTargetBinding, RafxCommandBufferDef,
+        RafxCommandPoolDef, RafxDepthStencilClearValue, RafxDepthStencilRenderTargetBinding,
+        RafxExtents3D, RafxFence, RafxFormat, RafxLoadOp, RafxQueue, RafxQueueType,
+        RafxResourceState, RafxResourceType, RafxResult, RafxSampleCount, RafxStoreOp,
+        RafxSwapchainDef, RafxSwapchainHelper, RafxTexture, RafxTextureBarrier, RafxTextureDef,
+    },
+    framework::{DynCommandPool, GraphicsPipelineRenderTargetMeta},
+    nodes::{
+        FeatureCommandWriter, RenderJobWriteContext, RenderPhaseIndex, RenderView, SubmitNode,
+    },
+};
+use raw_window_handle::HasRawWindowHandle;
+
+use crate::{FramePacketViews, RegisteredRenderPhases, RenderResources};
+
+/// Color format of the render targets of headless apps
+pub const OFFSCREEN_COLOR_FORMAT: RafxFormat = RafxFormat::R8G8B8A8_UNORM;
+pub const DEPTH_FORMAT: RafxFormat = RafxFormat::D32_SFLOAT;
+
+/// Frames that are recorded while earlier frames are still rendering
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+/// What a render feature prepared for the current frame, written by `Renderer`
+pub struct PreparedRenderFeature {
+    pub command_writer: Box<dyn FeatureCommandWriter<RenderJobWriteContext> + Send + Sync>,
+    /// Submit nodes of every view in `FramePacketViews`, in the same order, sorted by phase
+    pub view_submit_nodes: Vec<HashMap<RenderPhaseIndex, Vec<SubmitNode>>>,
+}
+
+/// Render features push what they prepared during `RenderStage::Prepare`. Written and cleared in
+/// `RenderStage::Submit`.
+#[derive(Default)]
+pub struct PreparedRenderFeatures(pub Vec<PreparedRenderFeature>);
+
+/// The textures a view is rendered into. Views of windows render into the swapchain instead of
+/// an own color texture.
+struct ViewTarget {
+    extents: (u32, u32),
+    color: Option<RafxTexture>,
+    depth: RafxTexture,
+}
+
+impl ViewTarget {
+    fn new(
+        render_resources: &RenderResources,
+        extents: (u32, u32),
+        offscreen: bool,
+    ) -> RafxResult<Self> {
+        let texture_def = |format, resource_type| RafxTextureDef {
+            extents: RafxExtents3D {
+                width: extents.0,
+                height: extents.1,
+                depth: 1,
+            },
+            format,
+            resource_type,
+            ..Default::default()
+        };
+        let device_context = &render_resources.device_context;
+
+        Ok(ViewTarget {
+            extents,
+            color: if offscreen {
+                Some(device_context.create_texture(&texture_def(
+                    OFFSCREEN_COLOR_FORMAT,
+                    RafxResourceType::TEXTURE | RafxResourceType::RENDER_TARGET_COLOR,
+                ))?)
+            } else {
+                None
+            },
+            depth: device_context.create_texture(&texture_def(
+                DEPTH_FORMAT,
+                RafxResourceType::RENDER_TARGET_DEPTH_STENCIL,
+            ))?,
+        })
+    }
+}
+
+/// A submitted frame. Headless frames are waited for with the fence before they are dropped,
+/// the swapchain waits for the frames of windows when the next image is acquired.
+struct FrameInFlight {
+    fence: Option<RafxFence>,
+    // Returned to the ResourceManager to be reset once the frame is done
+    _command_pool: DynCommandPool,
+    // Render targets replaced while the frame was in flight
+    _retired_targets: Vec<ViewTarget>,
+}
+
+/// Writes the prepared render features of every view and submits them, into the swapchain of the
+/// primary window or, for headless apps, into offscreen render targets. Created along with the
+/// `RenderResources`.
+pub struct Renderer {
+    queue: RafxQueue,
+    swapchain_helper: Option<RafxSwapchainHelper>,
+    /// Indexed like `FramePacketViews`
+    view_targets: Vec<ViewTarget>,
+    in_flight: VecDeque<FrameInFlight>,
+    frame_count: u64,
+}
+
+impl Renderer {
+    /// Renders into a swapchain of the window if there is one
+    pub(crate) fn new(
+        render_resources: &RenderResources,
+        window: Option<(&dyn HasRawWindowHandle, (u32, u32))>,
+    ) -> RafxResult<Self> {
+        let device_context = &render_resources.device_context;
+        let swapchain_helper = match window {
+            Some((window, (width, height))) => {
+                let swapchain = device_context.create_swapchain(
+                    window,
+                    &RafxSwapchainDef {
+                        width,
+                        height,
+                        enable_vsync: true,
+                    },
+                )?;
+                Some(RafxSwapchainHelper::new(device_context, swapchain, None)?)
+            }
+            None => None,
+        };
+
+        Ok(Renderer {
+            queue: device_context.create_queue(RafxQueueType::Graphics)?,
+            swapchain_helper,
+            view_targets: Vec::new(),
+            in_flight: VecDeque::new(),
+            frame_count: 0,
+        })
+    }
+
+    /// Number of frames submitted so far
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// The color texture the view at `view_index` of `FramePacketViews` was last rendered into,
+    /// None for views of windows
+    pub fn offscreen_color_texture(&self, view_index: usize) -> Option<&RafxTexture> {
+        self.view_targets.get(view_index)?.color.as_ref()
+    }
+
+    fn render(
+        &mut self,
+        render_resources: &RenderResources,
+        views: &[RenderView],
+        render_phase_indices: &[RenderPhaseIndex],
+        prepared_render_features: &[PreparedRenderFeature],
+        window_extents: Option<(u32, u32)>,
+    ) -> RafxResult<()> {
+        let swapchain_image = match (&mut self.swapchain_helper, window_extents) {
+            (Some(swapchain_helper), Some((width, height))) => {
+                Some(swapchain_helper.acquire_next_image(width, height, None)?)
+            }
+            // The window is minimized or closed
+            (Some(_), None) => return Ok(()),
+            (None, _) => None,
+        };
+
+        while self.in_flight.len() >= MAX_FRAMES_IN_FLIGHT {
+            if let Some(fence) = &self.in_flight[0].fence {
+                fence.wait()?;
+            }
+            self.in_flight.pop_front();
+        }
+
+        let offscreen = swapchain_image.is_none();
+        let mut retired_targets = Vec::new();
+        if self.view_targets.len() > views.len() {
+            retired_targets.extend(self.view_targets.drain(views.len()..));
+        }
+        for (view_index, view) in views.iter().enumerate() {
+            match self.view_targets.get(view_index) {
+                Some(view_target) if view_target.extents == view.extents() => {}
+                _ => {
+                    let view_target = ViewTarget::new(render_resources, view.extents(), offscreen)?;
+                    if view_index < self.view_targets.len() {
+                        retired_targets.push(std::mem::replace(
+                            &mut self.view_targets[view_index],
+                            view_target,
+                        ));
+                    } else {
+                        self.view_targets.push(view_target);
+                    }
+                }
+            }
+        }
+
+        let mut command_pool = render_resources
+            .resource_manager
+            .dyn_command_pool_allocator()
+            .allocate_dyn_pool(&self.queue, &RafxCommandPoolDef { transient: true }, 0)?;
+        let command_buffer = command_pool.allocate_dyn_command_buffer(&RafxCommandBufferDef {
+            is_secondary: false,
+        })?;
+        command_buffer.begin()?;
+
+        let color_format = match &swapchain_image {
+            Some(swapchain_image) => swapchain_image.swapchain_texture().texture_def().format,
+            None => OFFSCREEN_COLOR_FORMAT,
+        };
+        let mut write_context = RenderJobWriteContext {
+            device_context: render_resources.device_context.clone(),
+            resource_context: render_resources.resource_manager.resource_context(),
+            command_buffer: command_buffer.clone(),
+            render_target_meta: GraphicsPipelineRenderTargetMeta::new(
+                vec![color_format],
+                Some(DEPTH_FORMAT),
+                RafxSampleCount::SampleCount1,
+            ),
+        };
+
+        if let Some(swapchain_image) = &swapchain_image {
+            command_buffer.cmd_resource_barrier(
+                &[],
+                &[RafxTextureBarrier::state_transition(
+                    swapchain_image.swapchain_texture(),
+                    RafxResourceState::PRESENT,
+                    RafxResourceState::RENDER_TARGET,
+                )],
+            )?;
+        }
+
+        for (view_index, (view, view_target)) in views.iter().zip(&self.view_targets).enumerate() {
+            let (color, color_load_op) = match (&swapchain_image, &view_target.color) {
+                // Views of the window are drawn over each other
+                (Some(swapchain_image), _) => (
+                    swapchain_image.swapchain_texture(),
+                    if view_index == 0 {
+                        RafxLoadOp::Clear
+                    } else {
+                        RafxLoadOp::Load
+                    },
+                ),
+                (None, Some(color)) => {
+                    command_buffer.cmd_resource_barrier(
+                        &[],
+                        &[RafxTextureBarrier::state_transition(
+                            color,
+                            RafxResourceState::UNDEFINED,
+                            RafxResourceState::RENDER_TARGET,
+                        )],
+                    )?;
+                    (color, RafxLoadOp::Clear)
+                }
+                (None, None) => unreachable!("Offscreen view targets have a color texture"),
+            };
+            command_buffer.cmd_resource_barrier(
+                &[],
+                &[RafxTextureBarrier::state_transition(
+                    &view_target.depth,
+                    RafxResourceState::UNDEFINED,
+                    RafxResourceState::DEPTH_WRITE,
+                )],
+            )?;
+
+            command_buffer.cmd_begin_render_pass(
+                &[RafxColorRenderTargetBinding {
+                    texture: color,
+                    load_op: color_load_op,
+                    store_op: RafxStoreOp::Store,
+                    array_slice: None,
+                    mip_slice: None,
+                    clear_value: RafxColorClearValue([0.0, 0.0, 0.0, 1.0]),
+                    resolve_target: None,
+                    resolve_store_op: RafxStoreOp::DontCare,
+                    resolve_array_slice: None,
+                    resolve_mip_slice: None,
+                }],
+                Some(RafxDepthStencilRenderTargetBinding {
+                    texture: &view_target.depth,
+                    depth_load_op: RafxLoadOp::Clear,
+                    stencil_load_op: RafxLoadOp::DontCare,
+                    depth_store_op: RafxStoreOp::DontCare,
+                    stencil_store_op: RafxStoreOp::DontCare,
+                    array_slice: None,
+                    mip_slice: None,
+                    clear_value: RafxDepthStencilClearValue {
+                        depth: 1.0,
+                        stencil: 0,
+                    },
+                }),
+            )?;
+
+            // Phases in registration order, so the depth prepass comes before the opaque phase.
+            // Within a phase the features are written one after the other.
+            for &render_phase_index in render_phase_indices {
+                for prepared_render_feature in prepared_render_features {
+                    let submit_nodes = match prepared_render_feature
+                        .view_submit_nodes
+                        .get(view_index)
+                        .and_then(|view_submit_nodes| view_submit_nodes.get(&render_phase_index))
+                    {
+                        Some(submit_nodes) if !submit_nodes.is_empty() => submit_nodes,
+                        _ => continue,
+                    };
+
+                    let command_writer = &prepared_render_feature.command_writer;
+                    command_writer.apply_setup(&mut write_context, view, render_phase_index)?;
+                    for submit_node in submit_nodes {
+                        command_writer.render_element(
+                            &mut write_context,
+                            view,
+                            render_phase_index,
+                            submit_node.submit_node_id(),
+                        )?;
+                    }
+                    command_writer.revert_setup(&mut write_context, view, render_phase_index)?;
+                }
+            }
+
+            command_buffer.cmd_end_render_pass()?;
+        }
+
+        let fence = match swapchain_image {
+            Some(swapchain_image) => {
+                command_buffer.cmd_resource_barrier(
+                    &[],
+                    &[RafxTextureBarrier::state_transition(
+                        swapchain_image.swapchain_texture(),
+                        RafxResourceState::RENDER_TARGET,
+                        RafxResourceState::PRESENT,
+                    )],
+                )?;
+                command_buffer.end()?;
+                swapchain_image.present(&self.queue, &[&*command_buffer])?;
+                None
+            }
+            None => {
+                command_buffer.end()?;
+                let fence = render_resources.device_context.create_fence()?;
+                self.queue
+                    .submit(&[&*command_buffer], &[], &[], Some(&fence))?;
+                Some(fence)
+            }
+        };
+
+        self.in_flight.push_back(FrameInFlight {
+            fence,
+            _command_pool: command_pool,
+            _retired_targets: retired_targets,
+        });
+        self.frame_count += 1;
+        Ok(())
+    }
+}
+
+/// Writes and submits what the render features prepared for the views of the frame packet, then
+/// lets the `ResourceManager` release the resources of frames that finished rendering
+pub(crate) fn submit(
+    render_resources: Option<ResMut<RenderResources>>,
+    renderer: Option<ResMut<Renderer>>,
+    registered_render_phases: Res<RegisteredRenderPhases>,
+    frame_packet_views: Res<FramePacketViews>,
+    mut prepared_render_features: ResMut<PreparedRenderFeatures>,
+    windows: Option<Res<Windows>>,
+) {
+    let prepared_render_features = std::mem::take(&mut prepared_render_features.0);
+
+    let (mut render_resources, mut renderer) = match (render_resources, renderer) {
+        (Some(render_resources), Some(renderer)) => (render_resources, renderer),
+        _ => return,
+    };
+
+    let window_extents = windows
+        .as_ref()
+        .and_then(|windows| windows.get_primary())
+        .map(|window| (window.physical_width(), window.physical_height()))
+        .filter(|&(width, height)| width > 0 && height > 0);
+
+    if let Err(err) = renderer.render(
+        &render_resources,
+        &frame_packet_views.0,
+        &registered_render_phases.render_phase_indices(),
+        &prepared_render_features,
+        window_extents,
+    ) {
+        error!("Failed to render frame: {:?}", err);
+    }
+
+    if let Err(err) = render_resources.resource_manager.on_frame_complete() {
+        error!("Failed to complete frame: {:?}", err);
+    }
+}
@@ -0,0 +1,75 @@
+//! Helpers for testing the plugin and render features without a window
+
+use bevy::prelude::{App, AppBuilder, MinimalPlugins};
+use rafx::nodes::{
+    RenderPhase, RenderPhaseMaskBuilder, RenderRegistryBuilder, SubmitNode, SubmitNodeId,
+    ViewSubmitNodes,
+};
+
+use crate::{
+    phases::{
+        depth_prepass_render_phase::DepthPrepassRenderPhase,
+        opaque_render_phase::OpaqueRenderPhase, transparent_render_phase::TransparentRenderPhase,
+    },
+    BevyRafxPlugin, HeadlessRenderSettings,
+};
+
+/// Root of the workspace, which `COOKED_SHADERS_DIR` and the other asset paths are relative to
+pub const WORKSPACE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../..");
+
+/// Tests run in the directory of their crate, but the cooked shaders are loaded from the
+/// workspace. Every test sets the same directory, so tests running at the same time agree.
+pub fn set_workspace_dir() {
+    std::env::set_current_dir(WORKSPACE_DIR)
+        .unwrap_or_else(|err| panic!("Failed to change to {}: {}", WORKSPACE_DIR, err));
+}
+
+/// An app with `MinimalPlugins` and `BevyRafxPlugin` that renders headless
+pub fn headless_app() -> App {
+    headless_app_with(|_| {})
+}
+
+/// Like `headless_app`, with `build` called after `BevyRafxPlugin` is added, e.g. to add render
+/// features
+pub fn headless_app_with(build: impl FnOnce(&mut AppBuilder)) -> App {
+    set_workspace_dir();
+
+    let mut app_builder = App::build();
+    app_builder
+        .add_plugins(MinimalPlugins)
+        .insert_resource(HeadlessRenderSettings {
+            extents: (800, 600),
+        })
+        .add_plugin(BevyRafxPlugin);
+    build(&mut app_builder);
+    app_builder.app
+}
+
+/// Submit nodes of `P` at the given distances, each with its position as submit node id
+pub fn submit_nodes_at<P: RenderPhase>(distances: &[f32]) -> Vec<SubmitNode> {
+    // Render phase indices are global, so they are assigned in the same order as BevyRafxPlugin
+    // does for the apps of tests running at the same time
+    RenderRegistryBuilder::default()
+        .register_render_phase::<DepthPrepassRenderPhase>("DepthPrepass")
+        .register_render_phase::<OpaqueRenderPhase>("Opaque")
+        .register_render_phase::<TransparentRenderPhase>("Transparent")
+        .build();
+
+    let render_phase_mask = RenderPhaseMaskBuilder::default()
+        .add_render_phase::<P>()
+        .build();
+    let mut view_submit_nodes = ViewSubmitNodes::new(0, render_phase_mask);
+    for (submit_node_id, &distance) in distances.iter().enumerate() {
+        view_submit_nodes.add_submit_node::<P>(submit_node_id as SubmitNodeId, 0, distance);
+    }
+    view_submit_nodes
+        .submit_nodes(P::render_phase_index())
+        .to_vec()
+}
+
+pub fn submit_node_ids(submit_nodes: &[SubmitNode]) -> Vec<SubmitNodeId> {
+    submit_nodes
+        .iter()
+        .map(|submit_node| submit_node.submit_node_id())
+        .collect()
+}
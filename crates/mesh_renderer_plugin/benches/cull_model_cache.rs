@@ -0,0 +1,143 @@
+//! How building cull models scales with entities sharing a mesh versus unique meshes.
+//!
+//! `build` measures the time to get the cull models of every entity from a cold
+//! `CullModelCache`. The memory report prints the bytes held by the cache and by the cull models
+//! it hands out. The cache scales with unique meshes, the handed out cull models scale with
+//! entities because the `VisibilityRegion` takes them by value.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bevy::asset::{Handle, HandleId};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mesh_renderer_plugin::{
+    mesh::Indices, CullModelCache, CullModelKind, Mesh, PrimitiveTopology, VertexAttributeValues,
+};
+use rafx::visibility::CullModel;
+
+/// Counts the bytes currently allocated
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ENTITY_COUNTS: &[usize] = &[100, 1_000, 10_000];
+
+/// A grid of `size` x `size` quads, like a rock with a few hundred triangles
+fn grid_mesh(size: u16) -> Mesh {
+    let mut positions = Vec::new();
+    for y in 0..=size {
+        for x in 0..=size {
+            positions.push([x as f32, y as f32, ((x * y) % 3) as f32]);
+        }
+    }
+    let mut indices = Vec::new();
+    for y in 0..size {
+        for x in 0..size {
+            let i = y * (size + 1) + x;
+            indices.extend_from_slice(&[i, i + 1, i + size + 2, i, i + size + 2, i + size + 1]);
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        VertexAttributeValues::Float32x3(positions),
+    );
+    mesh.set_indices(Some(Indices::U16(indices)));
+    mesh
+}
+
+/// The mesh handle of every entity, either all the same or all different
+fn entity_meshes(entity_count: usize, unique: bool) -> Vec<Handle<Mesh>> {
+    let shared = Handle::weak(HandleId::random::<Mesh>());
+    (0..entity_count)
+        .map(|_| {
+            if unique {
+                Handle::weak(HandleId::random::<Mesh>())
+            } else {
+                shared.clone()
+            }
+        })
+        .collect()
+}
+
+fn build_cull_models(
+    cull_model_cache: &mut CullModelCache,
+    mesh: &Mesh,
+    mesh_handles: &[Handle<Mesh>],
+) -> Vec<CullModel> {
+    mesh_handles
+        .iter()
+        .map(|mesh_handle| {
+            cull_model_cache.get_or_build(mesh_handle, mesh, CullModelKind::Mesh, None)
+        })
+        .collect()
+}
+
+fn build(c: &mut Criterion) {
+    let mesh = grid_mesh(16);
+    let mut group = c.benchmark_group("build");
+    for &entity_count in ENTITY_COUNTS {
+        for &(name, unique) in &[("shared_mesh", false), ("unique_meshes", true)] {
+            let mesh_handles = entity_meshes(entity_count, unique);
+            group.bench_with_input(
+                BenchmarkId::new(name, entity_count),
+                &mesh_handles,
+                |b, mesh_handles| {
+                    b.iter_batched(
+                        CullModelCache::default,
+                        |mut cull_model_cache| {
+                            build_cull_models(&mut cull_model_cache, &mesh, mesh_handles)
+                        },
+                        BatchSize::LargeInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn memory(_: &mut Criterion) {
+    let mesh = grid_mesh(16);
+    for &entity_count in ENTITY_COUNTS {
+        for &(name, unique) in &[("shared_mesh", false), ("unique_meshes", true)] {
+            let mesh_handles = entity_meshes(entity_count, unique);
+
+            let before = ALLOCATED.load(Ordering::Relaxed);
+            let mut cull_model_cache = CullModelCache::default();
+            let cull_models = build_cull_models(&mut cull_model_cache, &mesh, &mesh_handles);
+            let cache_and_cull_models = ALLOCATED.load(Ordering::Relaxed) - before;
+            drop(cull_models);
+            let cache = ALLOCATED.load(Ordering::Relaxed) - before;
+
+            println!(
+                "memory/{}/{}: cache {} bytes, cull models {} bytes",
+                name,
+                entity_count,
+                cache,
+                cache_and_cull_models - cache
+            );
+        }
+    }
+}
+
+criterion_group!(benches, build, memory);
+criterion_main!(benches);
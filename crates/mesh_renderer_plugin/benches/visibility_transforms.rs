@@ -0,0 +1,94 @@
+//! Time to move the visibility objects of entities whose `GlobalTransform` changed, with
+//! `mesh_update_visibility_transforms` versus one entity after the other on a single thread.
+
+use bevy::{
+    ecs::system::{IntoSystem, System},
+    prelude::{Changed, GlobalTransform, Query, Without, World},
+    tasks::{ComputeTaskPool, TaskPool},
+};
+use bevy_rafx_plugin::{StaticVisibility, VisibilityComponent};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mesh_renderer_plugin::mesh_update_visibility_transforms;
+use rafx::visibility::{CullModel, EntityId, VisibilityRegion};
+
+const ENTITY_COUNTS: &[usize] = &[1_000, 10_000, 50_000];
+
+/// `entity_count` entities with dynamic visibility objects. Their transforms count as changed for
+/// a newly initialized system.
+fn moved_entities(entity_count: usize) -> World {
+    let mut world = World::new();
+    world.insert_resource(ComputeTaskPool(TaskPool::new()));
+    let visibility_region = VisibilityRegion::new();
+    for i in 0..entity_count {
+        let entity = world.spawn().id();
+        let handle = visibility_region
+            .register_dynamic_object(EntityId::from(entity), CullModel::sphere(1.0));
+        world.entity_mut(entity).insert_bundle((
+            GlobalTransform::from_xyz(i as f32, 0.0, 0.0),
+            VisibilityComponent {
+                handle: Some(handle),
+            },
+        ));
+    }
+    // Keeps the visibility objects alive
+    world.insert_resource(visibility_region);
+    world
+}
+
+fn serial_update_visibility_transforms(
+    query: Query<
+        (&GlobalTransform, &VisibilityComponent),
+        (Changed<GlobalTransform>, Without<StaticVisibility>),
+    >,
+) {
+    query.for_each(|(global_transform, visibility_component)| {
+        if let Some(handle) = visibility_component.handle.as_ref() {
+            handle.set_transform(
+                global_transform.translation,
+                global_transform.rotation,
+                global_transform.scale,
+            );
+        }
+    });
+}
+
+fn visibility_transforms(c: &mut Criterion) {
+    let mut group = c.benchmark_group("visibility_transforms");
+    group.sample_size(20);
+    for &entity_count in ENTITY_COUNTS {
+        group.bench_function(BenchmarkId::new("serial", entity_count), |b| {
+            b.iter_batched(
+                || {
+                    let mut world = moved_entities(entity_count);
+                    let mut system = serial_update_visibility_transforms.system();
+                    system.initialize(&mut world);
+                    (world, system)
+                },
+                |(mut world, mut system)| {
+                    system.run((), &mut world);
+                    (world, system)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function(BenchmarkId::new("parallel", entity_count), |b| {
+            b.iter_batched(
+                || {
+                    let mut world = moved_entities(entity_count);
+                    let mut system = mesh_update_visibility_transforms.system();
+                    system.initialize(&mut world);
+                    (world, system)
+                },
+                |(mut world, mut system)| {
+                    system.run((), &mut world);
+                    (world, system)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, visibility_transforms);
+criterion_main!(benches);
@@ -0,0 +1,564 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::{
+    asset::{Handle, HandleId},
+    ecs::reflect::ReflectComponent,
+    math::Vec3,
+    reflect::Reflect,
+};
+use bevy_render::{
+    mesh::{Indices, Mesh, VertexAttributeValues},
+    pipeline::PrimitiveTopology,
+};
+use rafx::{
+    rafx_visibility::{PolygonSoup, PolygonSoupIndex, VisibleBounds},
+    visibility::CullModel,
+};
+
+/// How a mesh entity is culled. Insert as a component to override the
+/// `MeshRendererPlugin::cull_model_kind` default for a single entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CullModelKind {
+    /// Sphere around the entity's origin, enclosing all vertices
+    BoundingSphere,
+    /// Axis aligned bounding box of all vertices, in model space
+    Aabb,
+    /// Quad spanning the X and Y extents of all vertices, for sprites and cards
+    Quad,
+    /// The exact triangles of the mesh
+    Mesh,
+}
+
+impl Default for CullModelKind {
+    fn default() -> Self {
+        CullModelKind::Mesh
+    }
+}
+
+/// Model space bounds of a mesh that are known up front, like the min and max of glTF position
+/// accessors. Saves computing them from `Mesh::ATTRIBUTE_POSITION`.
+#[derive(Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct MeshAabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl MeshAabb {
+    pub fn from_positions(positions: &[[f32; 3]]) -> Option<MeshAabb> {
+        let mut positions = positions.iter().map(|&position| Vec3::from(position));
+        let first = positions.next()?;
+        Some(positions.fold(
+            MeshAabb {
+                min: first,
+                max: first,
+            },
+            |aabb, position| MeshAabb {
+                min: aabb.min.min(position),
+                max: aabb.max.max(position),
+            },
+        ))
+    }
+
+    /// Radius of the smallest sphere around the origin that contains the box
+    pub fn bounding_sphere_radius(&self) -> f32 {
+        self.min.abs().max(self.max.abs()).length()
+    }
+
+    fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Bit patterns of the bounds, so they can be part of a cache key
+    fn to_bits(&self) -> [u32; 6] {
+        [
+            self.min.x.to_bits(),
+            self.min.y.to_bits(),
+            self.min.z.to_bits(),
+            self.max.x.to_bits(),
+            self.max.y.to_bits(),
+            self.max.z.to_bits(),
+        ]
+    }
+
+    /// Quad spanning the X and Y extents of the box, at the center of its Z extents
+    fn to_quad_polygon_soup(&self) -> PolygonSoup {
+        let (min, max, z) = (self.min, self.max, self.center().z);
+        PolygonSoup {
+            vertex_positions: vec![
+                [min.x, min.y, z].into(),
+                [max.x, min.y, z].into(),
+                [max.x, max.y, z].into(),
+                [min.x, max.y, z].into(),
+            ],
+            index: PolygonSoupIndex::Indexed16(vec![0, 1, 2, 0, 2, 3]),
+        }
+    }
+
+    fn to_polygon_soup(&self) -> PolygonSoup {
+        let (min, max) = (self.min, self.max);
+        let vertex_positions = vec![
+            [min.x, min.y, min.z].into(),
+            [max.x, min.y, min.z].into(),
+            [max.x, max.y, min.z].into(),
+            [min.x, max.y, min.z].into(),
+            [min.x, min.y, max.z].into(),
+            [max.x, min.y, max.z].into(),
+            [max.x, max.y, max.z].into(),
+            [min.x, max.y, max.z].into(),
+        ];
+        #[rustfmt::skip]
+        let indices = vec![
+            0, 2, 1, 0, 3, 2, // -Z
+            4, 5, 6, 4, 6, 7, // +Z
+            0, 1, 5, 0, 5, 4, // -Y
+            3, 6, 2, 3, 7, 6, // +Y
+            0, 4, 7, 0, 7, 3, // -X
+            1, 2, 6, 1, 6, 5, // +X
+        ];
+
+        PolygonSoup {
+            vertex_positions,
+            index: PolygonSoupIndex::Indexed16(indices),
+        }
+    }
+}
+
+/// A cull model that is built once per mesh and shared by every entity using it
+#[derive(Clone)]
+enum CachedCullModel {
+    VisibleBounds(Arc<VisibleBounds>),
+    Sphere(f32),
+    Quad(f32, f32),
+}
+
+impl CachedCullModel {
+    fn build(mesh: &Mesh, kind: CullModelKind, mesh_aabb: Option<&MeshAabb>) -> CachedCullModel {
+        let mesh_aabb = || {
+            mesh_aabb
+                .copied()
+                .or_else(|| MeshAabb::from_positions(&mesh_positions(mesh)))
+                .unwrap_or_default()
+        };
+
+        match kind {
+            CullModelKind::BoundingSphere => {
+                CachedCullModel::Sphere(mesh_aabb().bounding_sphere_radius())
+            }
+            CullModelKind::Aabb => CachedCullModel::VisibleBounds(Arc::new(VisibleBounds::from(
+                mesh_aabb().to_polygon_soup(),
+            ))),
+            CullModelKind::Quad => {
+                let mesh_aabb = mesh_aabb();
+                // Quad cull models are centered on the entity's origin
+                if mesh_aabb.center() == Vec3::ZERO {
+                    let extents = mesh_aabb.max - mesh_aabb.min;
+                    CachedCullModel::Quad(extents.x, extents.y)
+                } else {
+                    CachedCullModel::VisibleBounds(Arc::new(VisibleBounds::from(
+                        mesh_aabb.to_quad_polygon_soup(),
+                    )))
+                }
+            }
+            CullModelKind::Mesh => CachedCullModel::VisibleBounds(Arc::new(VisibleBounds::from(
+                mesh_to_polygon_soup(mesh).unwrap_or_else(|| mesh_aabb().to_polygon_soup()),
+            ))),
+        }
+    }
+
+    fn to_cull_model(&self) -> CullModel {
+        match self {
+            // The VisibilityRegion takes its cull models by value, so every object gets its own
+            // copy of the bounds. Only building them is shared.
+            CachedCullModel::VisibleBounds(visible_bounds) => {
+                CullModel::VisibleBounds(VisibleBounds::clone(visible_bounds))
+            }
+            CachedCullModel::Sphere(radius) => CullModel::sphere(*radius),
+            CachedCullModel::Quad(width, height) => CullModel::quad(*width, *height),
+        }
+    }
+}
+
+/// Identifies a cull model. Entities with the same mesh but different known bounds get
+/// different cull models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CullModelKey {
+    mesh_id: HandleId,
+    kind: CullModelKind,
+    mesh_aabb: Option<[u32; 6]>,
+}
+
+/// Cull models of every mesh in use, so a cull model is built once per mesh instead of once per
+/// entity converting and triangulating the mesh's positions and indices. Invalidated when the
+/// mesh asset changes. See `benches/cull_model_cache.rs` for how time and memory scale.
+#[derive(Default)]
+pub struct CullModelCache {
+    cull_models: HashMap<CullModelKey, CachedCullModel>,
+    build_count: usize,
+}
+
+impl CullModelCache {
+    pub fn get_or_build(
+        &mut self,
+        mesh_handle: &Handle<Mesh>,
+        mesh: &Mesh,
+        kind: CullModelKind,
+        mesh_aabb: Option<&MeshAabb>,
+    ) -> CullModel {
+        let build_count = &mut self.build_count;
+        let key = CullModelKey {
+            mesh_id: mesh_handle.id,
+            kind,
+            mesh_aabb: mesh_aabb.map(MeshAabb::to_bits),
+        };
+        self.cull_models
+            .entry(key)
+            .or_insert_with(|| {
+                *build_count += 1;
+                CachedCullModel::build(mesh, kind, mesh_aabb)
+            })
+            .to_cull_model()
+    }
+
+    pub fn invalidate(&mut self, mesh_id: HandleId) {
+        self.cull_models.retain(|key, _| key.mesh_id != mesh_id);
+    }
+
+    /// Number of cached cull models
+    pub fn len(&self) -> usize {
+        self.cull_models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cull_models.is_empty()
+    }
+
+    /// Number of cull models built since startup, including rebuilds after invalidation
+    pub fn build_count(&self) -> usize {
+        self.build_count
+    }
+}
+
+/// Model space positions of the mesh, converted from any vertex format.
+/// Missing components are 0, the W of 4 component positions is ignored.
+pub(crate) fn mesh_positions(mesh: &Mesh) -> Vec<[f32; 3]> {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        Some(positions) => positions,
+        None => return Vec::new(),
+    };
+
+    match positions {
+        VertexAttributeValues::Float32(values) => values.iter().map(|&x| [x, 0.0, 0.0]).collect(),
+        VertexAttributeValues::Sint32(values) => {
+            values.iter().map(|&x| [x as f32, 0.0, 0.0]).collect()
+        }
+        VertexAttributeValues::Uint32(values) => {
+            values.iter().map(|&x| [x as f32, 0.0, 0.0]).collect()
+        }
+        VertexAttributeValues::Float32x2(values) => {
+            values.iter().map(|&[x, y]| [x, y, 0.0]).collect()
+        }
+        VertexAttributeValues::Sint32x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Uint32x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Float32x3(values) => values.clone(),
+        VertexAttributeValues::Sint32x3(values) => values
+            .iter()
+            .map(|&[x, y, z]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Uint32x3(values) => values
+            .iter()
+            .map(|&[x, y, z]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Float32x4(values) => {
+            values.iter().map(|&[x, y, z, _]| [x, y, z]).collect()
+        }
+        VertexAttributeValues::Sint32x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Uint32x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Sint16x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Snorm16x2(values) => values
+            .iter()
+            .map(|&[x, y]| [snorm16(x), snorm16(y), 0.0])
+            .collect(),
+        VertexAttributeValues::Uint16x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Unorm16x2(values) => values
+            .iter()
+            .map(|&[x, y]| [unorm16(x), unorm16(y), 0.0])
+            .collect(),
+        VertexAttributeValues::Sint16x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Snorm16x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [snorm16(x), snorm16(y), snorm16(z)])
+            .collect(),
+        VertexAttributeValues::Uint16x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Unorm16x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [unorm16(x), unorm16(y), unorm16(z)])
+            .collect(),
+        VertexAttributeValues::Sint8x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Snorm8x2(values) => values
+            .iter()
+            .map(|&[x, y]| [snorm8(x), snorm8(y), 0.0])
+            .collect(),
+        VertexAttributeValues::Uint8x2(values) => values
+            .iter()
+            .map(|&[x, y]| [x as f32, y as f32, 0.0])
+            .collect(),
+        VertexAttributeValues::Unorm8x2(values) => values
+            .iter()
+            .map(|&[x, y]| [unorm8(x), unorm8(y), 0.0])
+            .collect(),
+        VertexAttributeValues::Sint8x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Snorm8x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [snorm8(x), snorm8(y), snorm8(z)])
+            .collect(),
+        VertexAttributeValues::Uint8x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [x as f32, y as f32, z as f32])
+            .collect(),
+        VertexAttributeValues::Unorm8x4(values) => values
+            .iter()
+            .map(|&[x, y, z, _]| [unorm8(x), unorm8(y), unorm8(z)])
+            .collect(),
+    }
+}
+
+fn snorm16(value: i16) -> f32 {
+    (value as f32 / i16::MAX as f32).max(-1.0)
+}
+
+fn unorm16(value: u16) -> f32 {
+    value as f32 / u16::MAX as f32
+}
+
+fn snorm8(value: i8) -> f32 {
+    (value as f32 / i8::MAX as f32).max(-1.0)
+}
+
+fn unorm8(value: u8) -> f32 {
+    value as f32 / u8::MAX as f32
+}
+
+/// Triangle list indices of the mesh. Non-indexed meshes index their vertices in order.
+/// Returns None for topologies without triangles, like lines and points.
+pub(crate) fn mesh_triangle_indices(mesh: &Mesh, vertex_count: usize) -> Option<PolygonSoupIndex> {
+    let indices = match mesh.indices() {
+        Some(Indices::U16(u16)) => PolygonSoupIndex::Indexed16(u16.clone()),
+        Some(Indices::U32(u32)) => PolygonSoupIndex::Indexed32(u32.clone()),
+        None => PolygonSoupIndex::Indexed32((0..vertex_count as u32).collect()),
+    };
+
+    match mesh.primitive_topology() {
+        PrimitiveTopology::TriangleList => Some(indices),
+        PrimitiveTopology::TriangleStrip => Some(match indices {
+            PolygonSoupIndex::Indexed16(indices) => {
+                PolygonSoupIndex::Indexed16(triangle_strip_to_list(&indices))
+            }
+            PolygonSoupIndex::Indexed32(indices) => {
+                PolygonSoupIndex::Indexed32(triangle_strip_to_list(&indices))
+            }
+        }),
+        PrimitiveTopology::PointList
+        | PrimitiveTopology::LineList
+        | PrimitiveTopology::LineStrip => None,
+    }
+}
+
+fn triangle_strip_to_list<T: Copy + PartialEq>(strip: &[T]) -> Vec<T> {
+    let mut list = Vec::with_capacity(strip.len().saturating_sub(2) * 3);
+    for (i, triangle) in strip.windows(3).enumerate() {
+        // Every other triangle is flipped to keep the winding order
+        let (a, b, c) = if i % 2 == 0 {
+            (triangle[0], triangle[1], triangle[2])
+        } else {
+            (triangle[1], triangle[0], triangle[2])
+        };
+        // Degenerate triangles are used to restart strips
+        if a != b && b != c && a != c {
+            list.extend_from_slice(&[a, b, c]);
+        }
+    }
+    list
+}
+
+/// Returns None for meshes without triangles, which are culled by their bounds instead
+fn mesh_to_polygon_soup(mesh: &Mesh) -> Option<PolygonSoup> {
+    let vertex_positions = mesh_positions(mesh);
+    let index = mesh_triangle_indices(mesh, vertex_positions.len())?;
+    let index_count = match &index {
+        PolygonSoupIndex::Indexed16(indices) => indices.len(),
+        PolygonSoupIndex::Indexed32(indices) => indices.len(),
+    };
+    if vertex_positions.is_empty() || index_count == 0 {
+        return None;
+    }
+
+    Some(PolygonSoup {
+        vertex_positions: vertex_positions
+            .into_iter()
+            .map(|floats| floats.into())
+            .collect(),
+        index,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strip_to_list() {
+        assert_eq!(
+            triangle_strip_to_list(&[0u16, 1, 2, 3, 4]),
+            vec![0, 1, 2, 2, 1, 3, 2, 3, 4]
+        );
+        // Restart with degenerate triangles
+        assert_eq!(
+            triangle_strip_to_list(&[0u32, 1, 2, 2, 3, 3, 4, 5]),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn positions_from_any_format() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x2(vec![[1.0, 2.0]]),
+        );
+        assert_eq!(mesh_positions(&mesh), vec![[1.0, 2.0, 0.0]]);
+
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Snorm8x4(vec![[127, -127, 0, 127]]),
+        );
+        assert_eq!(mesh_positions(&mesh), vec![[1.0, -1.0, 0.0]]);
+    }
+
+    #[test]
+    fn quads_keep_the_center_of_the_bounds() {
+        let mesh = Mesh::new(PrimitiveTopology::TriangleList);
+
+        let centered = MeshAabb {
+            min: Vec3::new(-1.0, -2.0, 0.0),
+            max: Vec3::new(1.0, 2.0, 0.0),
+        };
+        match CachedCullModel::build(&mesh, CullModelKind::Quad, Some(&centered)) {
+            CachedCullModel::Quad(width, height) => assert_eq!((width, height), (2.0, 4.0)),
+            _ => panic!("expected a quad"),
+        }
+
+        let offset = MeshAabb {
+            min: Vec3::new(1.0, 1.0, 2.0),
+            max: Vec3::new(3.0, 2.0, 4.0),
+        };
+        let quad = offset.to_quad_polygon_soup();
+        assert_eq!(
+            quad.vertex_positions
+                .iter()
+                .map(|position| [position.x, position.y, position.z])
+                .collect::<Vec<_>>(),
+            vec![
+                [1.0, 1.0, 3.0],
+                [3.0, 1.0, 3.0],
+                [3.0, 2.0, 3.0],
+                [1.0, 2.0, 3.0],
+            ]
+        );
+        assert!(matches!(
+            CachedCullModel::build(&mesh, CullModelKind::Quad, Some(&offset)),
+            CachedCullModel::VisibleBounds(_)
+        ));
+    }
+
+    #[test]
+    fn non_indexed_and_non_triangle_meshes() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+            ]),
+        );
+        match mesh_triangle_indices(&mesh, 3) {
+            Some(PolygonSoupIndex::Indexed32(indices)) => assert_eq!(indices, vec![0, 1, 2]),
+            _ => panic!("expected implicit indices"),
+        }
+
+        let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]),
+        );
+        assert!(mesh_to_polygon_soup(&mesh).is_none());
+        // Still gets a bounding volume
+        assert!(matches!(
+            CachedCullModel::build(&mesh, CullModelKind::Mesh, None),
+            CachedCullModel::VisibleBounds(_)
+        ));
+        match CachedCullModel::build(&mesh, CullModelKind::BoundingSphere, None) {
+            CachedCullModel::Sphere(radius) => assert_eq!(radius, 1.0),
+            _ => panic!("expected a sphere"),
+        }
+    }
+
+    #[test]
+    fn meshes_without_triangles_fall_back_to_bounds() {
+        // No positions at all
+        let mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        assert!(mesh_to_polygon_soup(&mesh).is_none());
+
+        // Indexed, but without any indices
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]),
+        );
+        mesh.set_indices(Some(Indices::U16(Vec::new())));
+        assert!(mesh_to_polygon_soup(&mesh).is_none());
+
+        // Strips too short for a triangle
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleStrip);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]),
+        );
+        assert!(mesh_to_polygon_soup(&mesh).is_none());
+        assert!(matches!(
+            CachedCullModel::build(&mesh, CullModelKind::Mesh, None),
+            CachedCullModel::VisibleBounds(_)
+        ));
+    }
+}
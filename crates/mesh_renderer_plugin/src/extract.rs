@@ -0,0 +1,295 @@
+use std::{any::TypeId, collections::HashMap};
+
+use bevy::{
+    asset::{AssetServer, HandleId, HandleUntyped},
+    log::warn,
+    math::Mat4,
+    prelude::{
+        AssetEvent, Assets, Entity, EventReader, GlobalTransform, Handle, Local, Query,
+        RemovedComponents, Res, ResMut,
+    },
+};
+use bevy_rafx_plugin::{
+    phases::{
+        depth_prepass_render_phase::DepthPrepassRenderPhase,
+        opaque_render_phase::OpaqueRenderPhase, transparent_render_phase::TransparentRenderPhase,
+    },
+    FramePacketViews,
+};
+use rafx::{
+    framework::DescriptorSetArc,
+    nodes::{FramePacket, RenderFeature, ViewSubmitNodes},
+};
+
+use crate::{
+    AlphaBlend, GpuMaterials, GpuMeshes, MaterialPasses, Mesh, MeshRenderFeature,
+    MeshRenderNodeSet, RafxMaterial, RafxMaterialPass,
+};
+
+/// The `RafxMaterial` of an `ExtractedMesh`
+#[derive(Clone)]
+pub struct ExtractedMaterial {
+    pub material: HandleUntyped,
+    pub is_transparent: bool,
+    /// None until the material passes of the material's type are created, and for meshes
+    /// without the vertex attributes its shaders need
+    pub material_passes: Option<MaterialPasses>,
+    /// None until the material is loaded and prepared
+    pub descriptor_set: Option<DescriptorSetArc>,
+}
+
+/// Everything the mesh feature needs from a visible mesh entity to prepare and draw it
+#[derive(Clone)]
+pub struct ExtractedMesh {
+    pub entity: Entity,
+    pub transform: Mat4,
+    pub mesh: Handle<Mesh>,
+    pub material: Option<ExtractedMaterial>,
+    pub is_transparent: bool,
+}
+
+/// Meshes extracted from the current `FramePacket`
+#[derive(Default)]
+pub struct ExtractedMeshes {
+    /// Indexed by frame node. None if the entity was despawned or lost its mesh since it was
+    /// found visible.
+    pub frame_nodes: Vec<Option<ExtractedMesh>>,
+    /// Submit nodes of every view in `FramePacketViews`, in the same order. Submit node ids are
+    /// frame node indices.
+    pub view_submit_nodes: Vec<ViewSubmitNodes>,
+}
+
+/// Materials of the visible mesh entities, filled by `extract_materials` for every registered
+/// `RafxMaterial` and taken by `mesh_extract`. Every material type has its own map, in
+/// registration order, so an entity with handles of several material types gets the one that was
+/// registered last, e.g. a custom material instead of the `StandardMaterial` of its `PbrBundle`.
+#[derive(Default)]
+pub(crate) struct ExtractedMaterials(Vec<(TypeId, HashMap<Entity, ExtractedMaterial>)>);
+
+impl ExtractedMaterials {
+    pub(crate) fn register<M: RafxMaterial>(&mut self) {
+        if self.position::<M>().is_none() {
+            self.0.push((TypeId::of::<M>(), HashMap::default()));
+        }
+    }
+
+    fn position<M: RafxMaterial>(&self) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|(material_type, _)| *material_type == TypeId::of::<M>())
+    }
+
+    fn of_type_mut<M: RafxMaterial>(&mut self) -> &mut HashMap<Entity, ExtractedMaterial> {
+        let position = self.position::<M>().unwrap_or_else(|| {
+            panic!(
+                "{} isn't registered with add_rafx_material",
+                std::any::type_name::<M>()
+            )
+        });
+        &mut self.0[position].1
+    }
+
+    /// The material of the entity whose type was registered last
+    fn take(&mut self, entity: Entity) -> Option<ExtractedMaterial> {
+        self.0
+            .iter_mut()
+            .rev()
+            .fold(None, |material, (_, extracted_materials)| {
+                let extracted_material = extracted_materials.remove(&entity);
+                material.or(extracted_material)
+            })
+    }
+
+    fn clear(&mut self) {
+        for (_, extracted_materials) in &mut self.0 {
+            extracted_materials.clear();
+        }
+    }
+}
+
+/// Extracts the `M` materials of the visible mesh entities of the current frame packet. Meshes
+/// that lack vertex attributes the material's shaders need are warned about once per entity and
+/// mesh, and get no material pass, so they aren't drawn.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn extract_materials<M: RafxMaterial>(
+    frame_packet: Res<Option<FramePacket>>,
+    mesh_render_nodes: Res<MeshRenderNodeSet>,
+    rafx_material_pass: Option<Res<RafxMaterialPass<M>>>,
+    materials: Res<Assets<M>>,
+    gpu_materials: Res<GpuMaterials<M>>,
+    gpu_meshes: Res<GpuMeshes>,
+    asset_server: Option<Res<AssetServer>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    removed_meshes: RemovedComponents<Handle<Mesh>>,
+    mut warned_meshes: Local<HashMap<Entity, HandleId>>,
+    mut extracted_materials: ResMut<ExtractedMaterials>,
+    query: Query<(&Handle<M>, &Handle<Mesh>)>,
+) {
+    // Warnings are remembered only as long as the mesh and the entity's mesh handle exist
+    for event in mesh_events.iter() {
+        if let AssetEvent::Removed { handle } = event {
+            warned_meshes.retain(|_, mesh_id| *mesh_id != handle.id);
+        }
+    }
+    for entity in removed_meshes.iter() {
+        warned_meshes.remove(&entity);
+    }
+
+    let frame_packet = match frame_packet.as_ref() {
+        Some(frame_packet) => frame_packet,
+        None => return,
+    };
+
+    let extracted_materials = extracted_materials.of_type_mut::<M>();
+    let material_passes =
+        rafx_material_pass.map(|rafx_material_pass| rafx_material_pass.material_passes.clone());
+
+    for frame_node in frame_packet.frame_nodes(MeshRenderFeature::feature_index()) {
+        let entity = match mesh_render_nodes.get(frame_node.render_node_index()) {
+            Some(mesh_render_node) => mesh_render_node.entity,
+            None => continue,
+        };
+        let (material, mesh_handle) = match query.get(entity) {
+            Ok(components) => components,
+            Err(_) => continue,
+        };
+
+        let mut has_vertex_inputs = true;
+        // The mesh was validated when it was loaded, and isn't drawn before it is uploaded
+        if let (Some(material_passes), Some(gpu_mesh)) =
+            (&material_passes, gpu_meshes.get(mesh_handle))
+        {
+            let errors = gpu_mesh.vertex_semantic_errors(
+                // Every pass of the material has the same vertex shader
+                material_passes
+                    .opaque
+                    .get_raw()
+                    .vertex_inputs
+                    .iter()
+                    .map(|vertex_input| vertex_input.semantic.as_str()),
+            );
+            has_vertex_inputs = errors.is_empty();
+
+            if !has_vertex_inputs && warned_meshes.get(&entity) != Some(&mesh_handle.id) {
+                let mesh_name = asset_server
+                    .as_ref()
+                    .and_then(|asset_server| asset_server.get_handle_path(mesh_handle))
+                    .map_or_else(
+                        || format!("{:?}", mesh_handle.id),
+                        |asset_path| match asset_path.label() {
+                            Some(label) => format!("{}#{}", asset_path.path().display(), label),
+                            None => asset_path.path().display().to_string(),
+                        },
+                    );
+                for error in &errors {
+                    warn!(
+                        "Entity {:?} isn't drawn with {}, its mesh {}: {}",
+                        entity,
+                        std::any::type_name::<M>(),
+                        mesh_name,
+                        error
+                    );
+                }
+                warned_meshes.insert(entity, mesh_handle.id);
+            }
+        }
+
+        extracted_materials.insert(
+            entity,
+            ExtractedMaterial {
+                material: material.clone_untyped(),
+                is_transparent: materials
+                    .get(material)
+                    .map_or(false, |material| material.is_transparent()),
+                material_passes: material_passes.clone().filter(|_| has_vertex_inputs),
+                descriptor_set: gpu_materials
+                    .get(material)
+                    .map(|gpu_material| gpu_material.descriptor_set.clone()),
+            },
+        );
+    }
+}
+
+/// Copies the visible mesh entities of the current frame packet into `ExtractedMeshes` and
+/// creates their submit nodes. Opaque meshes go into the depth prepass and opaque phases,
+/// transparent meshes are sorted back-to-front in the transparent phase.
+pub(crate) fn mesh_extract(
+    frame_packet: Res<Option<FramePacket>>,
+    frame_packet_views: Res<FramePacketViews>,
+    mesh_render_nodes: Res<MeshRenderNodeSet>,
+    mut extracted_meshes: ResMut<ExtractedMeshes>,
+    mut extracted_materials: ResMut<ExtractedMaterials>,
+    query: Query<(&GlobalTransform, &Handle<Mesh>, Option<&AlphaBlend>)>,
+) {
+    extracted_meshes.frame_nodes.clear();
+    extracted_meshes.view_submit_nodes.clear();
+
+    let frame_packet = match frame_packet.as_ref() {
+        Some(frame_packet) => frame_packet,
+        None => return,
+    };
+
+    let feature_index = MeshRenderFeature::feature_index();
+
+    for frame_node in frame_packet.frame_nodes(feature_index) {
+        let extracted_mesh = mesh_render_nodes
+            .get(frame_node.render_node_index())
+            .and_then(|mesh_render_node| {
+                let entity = mesh_render_node.entity;
+                let (global_transform, mesh, alpha_blend) = query.get(entity).ok()?;
+                let material = extracted_materials.take(entity);
+
+                Some(ExtractedMesh {
+                    entity,
+                    transform: global_transform.compute_matrix(),
+                    mesh: mesh.clone(),
+                    is_transparent: alpha_blend.is_some()
+                        || material
+                            .as_ref()
+                            .map_or(false, |material| material.is_transparent),
+                    material,
+                })
+            });
+
+        extracted_meshes.frame_nodes.push(extracted_mesh);
+    }
+    // Materials of entities that lost their mesh
+    extracted_materials.clear();
+
+    for view in &frame_packet_views.0 {
+        let mut view_submit_nodes = ViewSubmitNodes::new(feature_index, view.render_phase_mask());
+
+        for view_node in frame_packet.view_nodes(view, feature_index).unwrap_or(&[]) {
+            let frame_node_index = view_node.frame_node_index();
+            let extracted_mesh = match &extracted_meshes.frame_nodes[frame_node_index as usize] {
+                Some(extracted_mesh) => extracted_mesh,
+                None => continue,
+            };
+
+            let distance =
+                (view.eye_position() - extracted_mesh.transform.w_axis.truncate()).length();
+
+            // Phases that aren't in the view's render phase mask are skipped
+            if extracted_mesh.is_transparent {
+                view_submit_nodes.add_submit_node::<TransparentRenderPhase>(
+                    frame_node_index,
+                    0,
+                    distance,
+                );
+            } else {
+                view_submit_nodes.add_submit_node::<DepthPrepassRenderPhase>(
+                    frame_node_index,
+                    0,
+                    distance,
+                );
+                view_submit_nodes.add_submit_node::<OpaqueRenderPhase>(
+                    frame_node_index,
+                    0,
+                    distance,
+                );
+            }
+        }
+
+        extracted_meshes.view_submit_nodes.push(view_submit_nodes);
+    }
+}
@@ -0,0 +1,548 @@
+use std::{collections::HashMap, fmt};
+
+use bevy::{
+    asset::HandleId,
+    log::{debug, error},
+    prelude::{AssetEvent, Assets, EventReader, Handle, Local, Res, ResMut},
+};
+use bevy_rafx_plugin::RenderResources;
+use bevy_render::pipeline::VertexFormat;
+use rafx::{
+    api::{
+        RafxFormat, RafxIndexType, RafxPrimitiveTopology, RafxResourceState, RafxResourceType,
+        RafxResult,
+    },
+    framework::{BufferResource, ResourceArc, VertexDataLayout, VertexDataSetLayout},
+    rafx_visibility::PolygonSoupIndex,
+};
+
+use crate::{
+    cull_model::{mesh_positions, mesh_triangle_indices},
+    upload::{GpuUploads, UploadId},
+    Mesh, VertexAttributeValues,
+};
+
+/// The rafx semantic a mesh attribute is bound to in the `MESH_VERTEX_LAYOUT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexAttributeSemantic {
+    pub attribute: &'static str,
+    pub semantic: &'static str,
+    /// Meshes without the attribute get a default value, so shaders can always use it
+    pub has_default: bool,
+    /// The only format `mesh_vertices` reads, None if every format is converted
+    pub format: Option<VertexFormat>,
+}
+
+/// Every semantic of `MeshVertex`, which shader inputs can use with `@[semantic(...)]`
+pub const VERTEX_ATTRIBUTE_SEMANTICS: &[VertexAttributeSemantic] = &[
+    VertexAttributeSemantic {
+        attribute: Mesh::ATTRIBUTE_POSITION,
+        semantic: "POSITION",
+        has_default: false,
+        format: None,
+    },
+    VertexAttributeSemantic {
+        attribute: Mesh::ATTRIBUTE_NORMAL,
+        semantic: "NORMAL",
+        has_default: false,
+        format: Some(VertexFormat::Float32x3),
+    },
+    VertexAttributeSemantic {
+        attribute: Mesh::ATTRIBUTE_UV_0,
+        semantic: "TEXCOORD",
+        has_default: true,
+        format: Some(VertexFormat::Float32x2),
+    },
+    VertexAttributeSemantic {
+        attribute: Mesh::ATTRIBUTE_COLOR,
+        semantic: "COLOR",
+        has_default: true,
+        format: Some(VertexFormat::Float32x4),
+    },
+    VertexAttributeSemantic {
+        attribute: Mesh::ATTRIBUTE_TANGENT,
+        semantic: "TANGENT",
+        has_default: false,
+        format: Some(VertexFormat::Float32x4),
+    },
+];
+
+/// Vertex of the mesh shaders, with the `VERTEX_ATTRIBUTE_SEMANTICS`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct MeshVertex {
+    pub position: [f32; 4],
+    pub normal: [f32; 3],
+    pub tex_coord: [f32; 2],
+    pub color: [f32; 4],
+    pub tangent: [f32; 4],
+}
+
+lazy_static::lazy_static! {
+    pub static ref MESH_VERTEX_LAYOUT : VertexDataSetLayout = {
+        VertexDataLayout::build_vertex_layout(&MeshVertex::default(), |builder, vertex| {
+            builder.add_member(&vertex.position, "POSITION", RafxFormat::R32G32B32A32_SFLOAT);
+            builder.add_member(&vertex.normal, "NORMAL", RafxFormat::R32G32B32_SFLOAT);
+            builder.add_member(&vertex.tex_coord, "TEXCOORD", RafxFormat::R32G32_SFLOAT);
+            builder.add_member(&vertex.color, "COLOR", RafxFormat::R32G32B32A32_SFLOAT);
+            builder.add_member(&vertex.tangent, "TANGENT", RafxFormat::R32G32B32A32_SFLOAT);
+        }).into_set(RafxPrimitiveTopology::TriangleList)
+    };
+}
+
+/// Interleaves the attributes of the mesh into `MeshVertex`es. Vertices are white without
+/// `Mesh::ATTRIBUTE_COLOR`, missing normals, texture coordinates and tangents are zero.
+/// Attributes in another format than in `VERTEX_ATTRIBUTE_SEMANTICS` count as missing.
+pub fn mesh_vertices(mesh: &Mesh) -> Vec<MeshVertex> {
+    let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+        Some(VertexAttributeValues::Float32x3(normals)) => Some(normals),
+        _ => None,
+    };
+    let tex_coords = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(tex_coords)) => Some(tex_coords),
+        _ => None,
+    };
+    let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+        Some(VertexAttributeValues::Float32x4(colors)) => Some(colors),
+        _ => None,
+    };
+    let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+        Some(VertexAttributeValues::Float32x4(tangents)) => Some(tangents),
+        _ => None,
+    };
+
+    mesh_positions(mesh)
+        .into_iter()
+        .enumerate()
+        .map(|(i, [x, y, z])| MeshVertex {
+            position: [x, y, z, 1.0],
+            normal: normals
+                .and_then(|normals| normals.get(i).copied())
+                .unwrap_or_default(),
+            tex_coord: tex_coords
+                .and_then(|tex_coords| tex_coords.get(i).copied())
+                .unwrap_or_default(),
+            color: colors
+                .and_then(|colors| colors.get(i).copied())
+                .unwrap_or([1.0, 1.0, 1.0, 1.0]),
+            tangent: tangents
+                .and_then(|tangents| tangents.get(i).copied())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Why a mesh can't provide a vertex input of a shader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VertexSemanticError {
+    /// The semantic isn't one of the `VERTEX_ATTRIBUTE_SEMANTICS`
+    UnknownSemantic { semantic: String },
+    /// The mesh doesn't have the attribute the semantic is bound to
+    MissingAttribute {
+        semantic: &'static str,
+        attribute: &'static str,
+    },
+    /// The mesh has the attribute in a format `mesh_vertices` doesn't read
+    WrongFormat {
+        semantic: &'static str,
+        attribute: &'static str,
+        format: VertexFormat,
+        expected_format: VertexFormat,
+    },
+}
+
+impl VertexSemanticError {
+    pub fn semantic(&self) -> &str {
+        match self {
+            VertexSemanticError::UnknownSemantic { semantic } => semantic,
+            VertexSemanticError::MissingAttribute { semantic, .. }
+            | VertexSemanticError::WrongFormat { semantic, .. } => semantic,
+        }
+    }
+}
+
+impl fmt::Display for VertexSemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VertexSemanticError::UnknownSemantic { semantic } => write!(
+                f,
+                "semantic {} isn't provided by any mesh attribute",
+                semantic
+            ),
+            VertexSemanticError::MissingAttribute {
+                semantic,
+                attribute,
+            } => write!(
+                f,
+                "attribute {} is missing for semantic {}",
+                attribute, semantic
+            ),
+            VertexSemanticError::WrongFormat {
+                semantic,
+                attribute,
+                format,
+                expected_format,
+            } => write!(
+                f,
+                "attribute {} for semantic {} is {:?} instead of {:?}",
+                attribute, semantic, format, expected_format
+            ),
+        }
+    }
+}
+
+/// Checks that the mesh has an attribute in the right format for every semantic a shader needs,
+/// using the `VERTEX_ATTRIBUTE_SEMANTICS`. Attributes with a default are only checked when the
+/// mesh has them.
+pub fn vertex_semantic_errors<'a>(
+    mesh: &Mesh,
+    semantics: impl IntoIterator<Item = &'a str>,
+) -> Vec<VertexSemanticError> {
+    semantics
+        .into_iter()
+        .filter_map(|semantic| {
+            match VERTEX_ATTRIBUTE_SEMANTICS
+                .iter()
+                .find(|vertex_attribute_semantic| vertex_attribute_semantic.semantic == semantic)
+            {
+                Some(vertex_attribute_semantic) => {
+                    match mesh.attribute(vertex_attribute_semantic.attribute) {
+                        None if !vertex_attribute_semantic.has_default => {
+                            Some(VertexSemanticError::MissingAttribute {
+                                semantic: vertex_attribute_semantic.semantic,
+                                attribute: vertex_attribute_semantic.attribute,
+                            })
+                        }
+                        None => None,
+                        Some(values) => {
+                            let format = VertexFormat::from(values);
+                            match vertex_attribute_semantic.format {
+                                Some(expected_format) if format != expected_format => {
+                                    Some(VertexSemanticError::WrongFormat {
+                                        semantic: vertex_attribute_semantic.semantic,
+                                        attribute: vertex_attribute_semantic.attribute,
+                                        format,
+                                        expected_format,
+                                    })
+                                }
+                                _ => None,
+                            }
+                        }
+                    }
+                }
+                None => Some(VertexSemanticError::UnknownSemantic {
+                    semantic: semantic.to_string(),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// A mesh uploaded to the GPU, drawn as an indexed triangle list of `MeshVertex`es
+#[derive(Clone)]
+pub struct GpuMesh {
+    pub vertex_buffer: ResourceArc<BufferResource>,
+    pub index_buffer: ResourceArc<BufferResource>,
+    pub index_type: RafxIndexType,
+    pub index_count: u32,
+    /// The `vertex_semantic_errors` of every `VERTEX_ATTRIBUTE_SEMANTICS`, found when the mesh
+    /// was loaded
+    pub semantic_errors: Vec<VertexSemanticError>,
+}
+
+impl GpuMesh {
+    /// Enqueues the upload of the mesh's vertices and indices into device local buffers, which
+    /// can be used once the returned upload is complete. Returns None for meshes without
+    /// triangles, which would need empty buffers.
+    pub fn new(
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+        mesh: &Mesh,
+    ) -> RafxResult<Option<(UploadId, GpuMesh)>> {
+        let vertices = mesh_vertices(mesh);
+        let indices = match mesh_triangle_indices(mesh, vertices.len()) {
+            Some(indices) => indices,
+            None => return Ok(None),
+        };
+        let index_count = match &indices {
+            PolygonSoupIndex::Indexed16(indices) => indices.len(),
+            PolygonSoupIndex::Indexed32(indices) => indices.len(),
+        };
+        if vertices.is_empty() || index_count == 0 {
+            return Ok(None);
+        }
+
+        let resources = render_resources.resource_manager.resources();
+
+        let (upload_id, vertex_buffer) = gpu_uploads.upload_buffer(
+            render_resources,
+            &vertices,
+            RafxResourceType::VERTEX_BUFFER,
+            RafxResourceState::VERTEX_AND_CONSTANT_BUFFER,
+        )?;
+
+        // Enqueued in the same frame, so both are in the same upload
+        let ((_, index_buffer), index_type) = match &indices {
+            PolygonSoupIndex::Indexed16(indices) => (
+                gpu_uploads.upload_buffer(
+                    render_resources,
+                    indices,
+                    RafxResourceType::INDEX_BUFFER,
+                    RafxResourceState::INDEX_BUFFER,
+                )?,
+                RafxIndexType::Uint16,
+            ),
+            PolygonSoupIndex::Indexed32(indices) => (
+                gpu_uploads.upload_buffer(
+                    render_resources,
+                    indices,
+                    RafxResourceType::INDEX_BUFFER,
+                    RafxResourceState::INDEX_BUFFER,
+                )?,
+                RafxIndexType::Uint32,
+            ),
+        };
+
+        Ok(Some((
+            upload_id,
+            GpuMesh {
+                vertex_buffer: resources.insert_buffer(vertex_buffer),
+                index_buffer: resources.insert_buffer(index_buffer),
+                index_type,
+                index_count: index_count as u32,
+                semantic_errors: vertex_semantic_errors(
+                    mesh,
+                    VERTEX_ATTRIBUTE_SEMANTICS
+                        .iter()
+                        .map(|vertex_attribute_semantic| vertex_attribute_semantic.semantic),
+                ),
+            },
+        )))
+    }
+
+    /// Like `vertex_semantic_errors`, without looking at the mesh again
+    pub fn vertex_semantic_errors<'a>(
+        &self,
+        semantics: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<VertexSemanticError> {
+        semantics
+            .into_iter()
+            .filter_map(|semantic| {
+                if VERTEX_ATTRIBUTE_SEMANTICS
+                    .iter()
+                    .any(|vertex_attribute_semantic| vertex_attribute_semantic.semantic == semantic)
+                {
+                    self.semantic_errors
+                        .iter()
+                        .find(|error| error.semantic() == semantic)
+                        .cloned()
+                } else {
+                    Some(VertexSemanticError::UnknownSemantic {
+                        semantic: semantic.to_string(),
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+/// The `GpuMesh` of every uploaded `Mesh` asset, used by the mesh feature to draw extracted
+/// meshes. Modified meshes are drawn as they were until their new upload is complete.
+#[derive(Default)]
+pub struct GpuMeshes {
+    gpu_meshes: HashMap<HandleId, GpuMesh>,
+    uploading: HashMap<HandleId, (UploadId, GpuMesh)>,
+}
+
+impl GpuMeshes {
+    pub fn get(&self, mesh: &Handle<Mesh>) -> Option<&GpuMesh> {
+        self.gpu_meshes.get(&mesh.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.gpu_meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gpu_meshes.is_empty()
+    }
+
+    fn upload(
+        &mut self,
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+        mesh_id: HandleId,
+        mesh: &Mesh,
+    ) {
+        match GpuMesh::new(render_resources, gpu_uploads, mesh) {
+            Ok(Some(uploading_mesh)) => {
+                self.uploading.insert(mesh_id, uploading_mesh);
+            }
+            Ok(None) => {
+                debug!("Mesh {:?} has no triangles, it isn't uploaded", mesh_id);
+                self.remove(mesh_id);
+            }
+            Err(err) => {
+                error!("Failed to upload mesh {:?}: {:?}", mesh_id, err);
+                self.remove(mesh_id);
+            }
+        }
+    }
+
+    fn remove(&mut self, mesh_id: HandleId) {
+        self.gpu_meshes.remove(&mesh_id);
+        self.uploading.remove(&mesh_id);
+    }
+
+    /// Makes the meshes whose upload is complete available
+    fn finish_uploads(&mut self, gpu_uploads: &GpuUploads) {
+        let uploaded_meshes = self
+            .uploading
+            .iter()
+            .filter(|(_, (upload_id, _))| gpu_uploads.is_complete(*upload_id))
+            .map(|(&mesh_id, _)| mesh_id)
+            .collect::<Vec<_>>();
+        for mesh_id in uploaded_meshes {
+            let (_, gpu_mesh) = self.uploading.remove(&mesh_id).unwrap();
+            self.gpu_meshes.insert(mesh_id, gpu_mesh);
+        }
+    }
+}
+
+/// Uploads created and modified `Mesh` assets and releases removed ones. Meshes that were loaded
+/// before the `RenderResources` existed are uploaded once they do.
+pub(crate) fn upload_meshes(
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut uploaded_existing_meshes: Local<bool>,
+    render_resources: Option<Res<RenderResources>>,
+    meshes: Res<Assets<Mesh>>,
+    mut gpu_uploads: ResMut<GpuUploads>,
+    mut gpu_meshes: ResMut<GpuMeshes>,
+) {
+    let render_resources = match render_resources {
+        Some(render_resources) => render_resources,
+        None => return,
+    };
+
+    gpu_meshes.finish_uploads(&gpu_uploads);
+
+    if !*uploaded_existing_meshes {
+        for (mesh_id, mesh) in meshes.iter() {
+            gpu_meshes.upload(&render_resources, &mut gpu_uploads, mesh_id, mesh);
+        }
+        *uploaded_existing_meshes = true;
+
+        // Pending events are about the meshes that were just uploaded as they are now
+        mesh_events.iter().for_each(drop);
+        return;
+    }
+
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                if let Some(mesh) = meshes.get(handle) {
+                    gpu_meshes.upload(&render_resources, &mut gpu_uploads, handle.id, mesh);
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                gpu_meshes.remove(handle.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PrimitiveTopology;
+
+    #[test]
+    fn vertices_with_and_without_colors() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![[1.0, 2.0, 3.0]]),
+        );
+        assert_eq!(
+            mesh_vertices(&mesh),
+            vec![MeshVertex {
+                position: [1.0, 2.0, 3.0, 1.0],
+                normal: [0.0, 0.0, 0.0],
+                tex_coord: [0.0, 0.0],
+                color: [1.0, 1.0, 1.0, 1.0],
+                tangent: [0.0, 0.0, 0.0, 0.0],
+            }]
+        );
+
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_COLOR,
+            VertexAttributeValues::Float32x4(vec![[1.0, 0.0, 0.0, 0.5]]),
+        );
+        assert_eq!(mesh_vertices(&mesh)[0].color, [1.0, 0.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn vertex_semantics() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x3(vec![[1.0, 2.0, 3.0]]),
+        );
+
+        // Colors have a default
+        assert!(vertex_semantic_errors(&mesh, vec!["POSITION", "COLOR"]).is_empty());
+        assert_eq!(
+            vertex_semantic_errors(&mesh, vec!["POSITION", "NORMAL", "TANGENT", "BINORMAL"]),
+            vec![
+                VertexSemanticError::MissingAttribute {
+                    semantic: "NORMAL",
+                    attribute: Mesh::ATTRIBUTE_NORMAL,
+                },
+                VertexSemanticError::MissingAttribute {
+                    semantic: "TANGENT",
+                    attribute: Mesh::ATTRIBUTE_TANGENT,
+                },
+                VertexSemanticError::UnknownSemantic {
+                    semantic: "BINORMAL".to_string(),
+                },
+            ]
+        );
+
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_NORMAL,
+            VertexAttributeValues::Float32x3(vec![[0.0, 1.0, 0.0]]),
+        );
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_TANGENT,
+            VertexAttributeValues::Float32x4(vec![[1.0, 0.0, 0.0, 1.0]]),
+        );
+        assert!(vertex_semantic_errors(&mesh, vec!["POSITION", "NORMAL", "TANGENT"]).is_empty());
+        assert_eq!(mesh_vertices(&mesh)[0].normal, [0.0, 1.0, 0.0]);
+        assert_eq!(mesh_vertices(&mesh)[0].tangent, [1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn vertex_attribute_formats() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            VertexAttributeValues::Float32x2(vec![[1.0, 2.0]]),
+        );
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_COLOR,
+            VertexAttributeValues::Float32x3(vec![[1.0, 0.0, 0.0]]),
+        );
+
+        // Positions of any format are converted, colors would be white
+        assert_eq!(
+            vertex_semantic_errors(&mesh, vec!["POSITION", "COLOR"]),
+            vec![VertexSemanticError::WrongFormat {
+                semantic: "COLOR",
+                attribute: Mesh::ATTRIBUTE_COLOR,
+                format: VertexFormat::Float32x3,
+                expected_format: VertexFormat::Float32x4,
+            }]
+        );
+        assert_eq!(mesh_vertices(&mesh)[0].color, [1.0, 1.0, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::HandleId,
+    log::{error, warn},
+    prelude::{AssetEvent, Assets, EventReader, Handle, Local, Res, ResMut},
+};
+use bevy_rafx_plugin::RenderResources;
+use bevy_render::{
+    pipeline::CompareFunction,
+    texture::{
+        AddressMode, FilterMode, SamplerDescriptor, Texture, TextureDimension, TextureFormat,
+    },
+};
+use rafx::{
+    api::{
+        RafxAddressMode, RafxCompareOp, RafxError, RafxExtents3D, RafxFilterType, RafxFormat,
+        RafxMipMapMode, RafxResourceType, RafxResult, RafxSamplerDef, RafxTextureDef,
+    },
+    framework::{ImageViewResource, ResourceArc, SamplerResource},
+};
+
+use crate::upload::{GpuUploads, UploadId};
+
+/// The rafx format of a bevy `TextureFormat`. The glTF loader sets `Rgba8Unorm` for textures
+/// with linear data, like normal maps, while color textures stay `Rgba8UnormSrgb`.
+pub fn rafx_format(format: TextureFormat) -> Option<RafxFormat> {
+    Some(match format {
+        TextureFormat::R8Unorm => RafxFormat::R8_UNORM,
+        TextureFormat::R8Snorm => RafxFormat::R8_SNORM,
+        TextureFormat::R8Uint => RafxFormat::R8_UINT,
+        TextureFormat::R8Sint => RafxFormat::R8_SINT,
+        TextureFormat::R16Uint => RafxFormat::R16_UINT,
+        TextureFormat::R16Sint => RafxFormat::R16_SINT,
+        TextureFormat::R16Float => RafxFormat::R16_SFLOAT,
+        TextureFormat::Rg8Unorm => RafxFormat::R8G8_UNORM,
+        TextureFormat::Rg8Snorm => RafxFormat::R8G8_SNORM,
+        TextureFormat::Rg8Uint => RafxFormat::R8G8_UINT,
+        TextureFormat::Rg8Sint => RafxFormat::R8G8_SINT,
+        TextureFormat::R32Uint => RafxFormat::R32_UINT,
+        TextureFormat::R32Sint => RafxFormat::R32_SINT,
+        TextureFormat::R32Float => RafxFormat::R32_SFLOAT,
+        TextureFormat::Rg16Uint => RafxFormat::R16G16_UINT,
+        TextureFormat::Rg16Sint => RafxFormat::R16G16_SINT,
+        TextureFormat::Rg16Float => RafxFormat::R16G16_SFLOAT,
+        TextureFormat::Rgba8Unorm => RafxFormat::R8G8B8A8_UNORM,
+        TextureFormat::Rgba8UnormSrgb => RafxFormat::R8G8B8A8_SRGB,
+        TextureFormat::Rgba8Snorm => RafxFormat::R8G8B8A8_SNORM,
+        TextureFormat::Rgba8Uint => RafxFormat::R8G8B8A8_UINT,
+        TextureFormat::Rgba8Sint => RafxFormat::R8G8B8A8_SINT,
+        TextureFormat::Bgra8Unorm => RafxFormat::B8G8R8A8_UNORM,
+        TextureFormat::Bgra8UnormSrgb => RafxFormat::B8G8R8A8_SRGB,
+        TextureFormat::Rgb10a2Unorm => RafxFormat::A2B10G10R10_UNORM_PACK32,
+        TextureFormat::Rg11b10Float => RafxFormat::B10G11R11_UFLOAT_PACK32,
+        TextureFormat::Rg32Uint => RafxFormat::R32G32_UINT,
+        TextureFormat::Rg32Sint => RafxFormat::R32G32_SINT,
+        TextureFormat::Rg32Float => RafxFormat::R32G32_SFLOAT,
+        TextureFormat::Rgba16Uint => RafxFormat::R16G16B16A16_UINT,
+        TextureFormat::Rgba16Sint => RafxFormat::R16G16B16A16_SINT,
+        TextureFormat::Rgba16Float => RafxFormat::R16G16B16A16_SFLOAT,
+        TextureFormat::Rgba32Uint => RafxFormat::R32G32B32A32_UINT,
+        TextureFormat::Rgba32Sint => RafxFormat::R32G32B32A32_SINT,
+        TextureFormat::Rgba32Float => RafxFormat::R32G32B32A32_SFLOAT,
+        TextureFormat::Depth32Float => RafxFormat::D32_SFLOAT,
+        TextureFormat::Depth24Plus => RafxFormat::X8_D24_UNORM_PACK32,
+        TextureFormat::Depth24PlusStencil8 => RafxFormat::D24_UNORM_S8_UINT,
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    })
+}
+
+fn rafx_filter_type(filter_mode: FilterMode) -> RafxFilterType {
+    match filter_mode {
+        FilterMode::Nearest => RafxFilterType::Nearest,
+        FilterMode::Linear => RafxFilterType::Linear,
+    }
+}
+
+fn rafx_address_mode(address_mode: AddressMode) -> RafxAddressMode {
+    match address_mode {
+        AddressMode::ClampToEdge => RafxAddressMode::ClampToEdge,
+        AddressMode::Repeat => RafxAddressMode::Repeat,
+        AddressMode::MirrorRepeat => RafxAddressMode::Mirror,
+    }
+}
+
+fn rafx_compare_op(compare_function: CompareFunction) -> RafxCompareOp {
+    match compare_function {
+        CompareFunction::Never => RafxCompareOp::Never,
+        CompareFunction::Less => RafxCompareOp::Less,
+        CompareFunction::Equal => RafxCompareOp::Equal,
+        CompareFunction::LessEqual => RafxCompareOp::LessOrEqual,
+        CompareFunction::Greater => RafxCompareOp::Greater,
+        CompareFunction::NotEqual => RafxCompareOp::NotEqual,
+        CompareFunction::GreaterEqual => RafxCompareOp::GreaterOrEqual,
+        CompareFunction::Always => RafxCompareOp::Always,
+    }
+}
+
+pub fn rafx_sampler_def(sampler: &SamplerDescriptor) -> RafxSamplerDef {
+    RafxSamplerDef {
+        min_filter: rafx_filter_type(sampler.min_filter),
+        mag_filter: rafx_filter_type(sampler.mag_filter),
+        mip_map_mode: match sampler.mipmap_filter {
+            FilterMode::Nearest => RafxMipMapMode::Nearest,
+            FilterMode::Linear => RafxMipMapMode::Linear,
+        },
+        address_mode_u: rafx_address_mode(sampler.address_mode_u),
+        address_mode_v: rafx_address_mode(sampler.address_mode_v),
+        address_mode_w: rafx_address_mode(sampler.address_mode_w),
+        mip_lod_bias: 0.0,
+        max_anisotropy: sampler
+            .anisotropy_clamp
+            .map_or(0.0, |anisotropy_clamp| anisotropy_clamp.get() as f32),
+        compare_op: sampler
+            .compare_function
+            .map_or(RafxCompareOp::Never, rafx_compare_op),
+    }
+}
+
+/// Number of mip levels down to 1x1
+fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Generates the mip chain of an image with `channels` 8 bit channels per pixel, with a box
+/// filter. Color channels of sRGB images are averaged in linear space, like alpha already is.
+/// Returns every level after the first.
+pub fn generate_mips(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    channels: usize,
+    srgb: bool,
+) -> Vec<Vec<u8>> {
+    let mut mips: Vec<Vec<u8>> = Vec::new();
+    let (mut width, mut height) = (width as usize, height as usize);
+
+    while width > 1 || height > 1 {
+        let previous = mips.last().map_or(data, |mip| mip.as_slice());
+        let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+
+        let mut mip = Vec::with_capacity(mip_width * mip_height * channels);
+        for y in 0..mip_height {
+            for x in 0..mip_width {
+                for channel in 0..channels {
+                    let is_color = srgb && channel < 3;
+                    let mut sum = 0.0;
+                    for &(sample_x, sample_y) in &[
+                        (x * 2, y * 2),
+                        (x * 2 + 1, y * 2),
+                        (x * 2, y * 2 + 1),
+                        (x * 2 + 1, y * 2 + 1),
+                    ] {
+                        // Odd sizes repeat the last row or column
+                        let sample_x = sample_x.min(width - 1);
+                        let sample_y = sample_y.min(height - 1);
+                        let value = previous[(sample_y * width + sample_x) * channels + channel];
+                        sum += if is_color {
+                            srgb_to_linear(value)
+                        } else {
+                            value as f32 / 255.0
+                        };
+                    }
+
+                    let average = sum / 4.0;
+                    mip.push(if is_color {
+                        linear_to_srgb(average)
+                    } else {
+                        (average * 255.0).round() as u8
+                    });
+                }
+            }
+        }
+
+        mips.push(mip);
+        width = mip_width;
+        height = mip_height;
+    }
+
+    mips
+}
+
+/// Channel count and sRGB-ness of the formats that mips are generated for
+fn mip_generation_format(format: TextureFormat) -> Option<(usize, bool)> {
+    match format {
+        TextureFormat::R8Unorm => Some((1, false)),
+        TextureFormat::Rg8Unorm => Some((2, false)),
+        TextureFormat::Rgba8Unorm | TextureFormat::Bgra8Unorm => Some((4, false)),
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Bgra8UnormSrgb => Some((4, true)),
+        _ => None,
+    }
+}
+
+/// A texture uploaded to the GPU with its full mip chain, and the sampler it is drawn with
+pub struct GpuTexture {
+    pub image_view: ResourceArc<ImageViewResource>,
+    pub sampler: ResourceArc<SamplerResource>,
+    pub mip_count: u32,
+}
+
+impl GpuTexture {
+    /// Enqueues the upload of the texture with its mip chain, see `from_mips`. Mips are only
+    /// generated for 8 bit formats, textures in other formats get a single level and alias when
+    /// minified.
+    pub fn new(
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+        texture: &Texture,
+    ) -> RafxResult<(UploadId, GpuTexture)> {
+        if texture.dimension != TextureDimension::D2 {
+            return Err(RafxError::StringError(format!(
+                "Only 2D textures are supported, not {:?}",
+                texture.dimension
+            )));
+        }
+        let format = rafx_format(texture.format).ok_or_else(|| {
+            RafxError::StringError(format!("Unsupported texture format {:?}", texture.format))
+        })?;
+
+        let (width, height) = (texture.size.width, texture.size.height);
+        let mut mips = vec![texture.data.clone()];
+        match mip_generation_format(texture.format) {
+            Some((channels, srgb)) => {
+                mips.extend(generate_mips(&texture.data, width, height, channels, srgb))
+            }
+            None if mip_level_count(width, height) > 1 => warn!(
+                "No mips are generated for {:?} textures, only the first of {} levels is uploaded",
+                texture.format,
+                mip_level_count(width, height)
+            ),
+            None => {}
+        }
+
+        Self::from_mips(
+            render_resources,
+            gpu_uploads,
+            format,
+            width,
+            height,
+            &mips,
+            &rafx_sampler_def(&texture.sampler),
+        )
+    }
+
+    /// Enqueues the upload of the given mip levels, largest first. The texture can be used once
+    /// the returned upload is complete.
+    pub fn from_mips(
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+        format: RafxFormat,
+        width: u32,
+        height: u32,
+        mips: &[Vec<u8>],
+        sampler_def: &RafxSamplerDef,
+    ) -> RafxResult<(UploadId, GpuTexture)> {
+        let resources = render_resources.resource_manager.resources();
+
+        let (upload_id, image) = gpu_uploads.upload_texture(
+            render_resources,
+            &RafxTextureDef {
+                extents: RafxExtents3D {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                mip_count: mips.len() as u32,
+                format,
+                resource_type: RafxResourceType::TEXTURE,
+                ..Default::default()
+            },
+            mips,
+        )?;
+
+        let image = resources.insert_image(image);
+        Ok((
+            upload_id,
+            GpuTexture {
+                image_view: resources.get_or_create_image_view(&image, None)?,
+                sampler: resources.get_or_create_sampler(sampler_def)?,
+                mip_count: mips.len() as u32,
+            },
+        ))
+    }
+
+    /// A 1x1 white texture, bound in place of textures that a material doesn't have
+    pub fn white(
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+    ) -> RafxResult<(UploadId, GpuTexture)> {
+        Self::from_mips(
+            render_resources,
+            gpu_uploads,
+            RafxFormat::R8G8B8A8_UNORM,
+            1,
+            1,
+            &[vec![255; 4]],
+            &rafx_sampler_def(&SamplerDescriptor::default()),
+        )
+    }
+}
+
+/// The `GpuTexture` of every uploaded `Texture` asset. Modified textures are bound as they were
+/// until their new upload is complete.
+#[derive(Default)]
+pub struct GpuTextures {
+    textures: HashMap<HandleId, GpuTexture>,
+    uploading: HashMap<HandleId, (UploadId, GpuTexture)>,
+    /// Textures whose upload completed this frame
+    uploaded: Vec<HandleId>,
+    white: Option<GpuTexture>,
+    uploading_white: Option<(UploadId, GpuTexture)>,
+}
+
+impl GpuTextures {
+    pub fn get(&self, texture: &Handle<Texture>) -> Option<&GpuTexture> {
+        self.textures.get(&texture.id)
+    }
+
+    /// The texture to bind for a texture that doesn't exist or isn't uploaded (yet)
+    pub fn get_or_white(&self, texture: Option<&Handle<Texture>>) -> Option<&GpuTexture> {
+        texture
+            .and_then(|texture| self.get(texture))
+            .or_else(|| self.white.as_ref())
+    }
+
+    /// Whether the white texture is uploaded, so materials can be prepared
+    pub fn has_white(&self) -> bool {
+        self.white.is_some()
+    }
+
+    /// Textures whose upload completed this frame, which materials using them are prepared
+    /// again for
+    pub fn uploaded(&self) -> &[HandleId] {
+        &self.uploaded
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+
+    fn upload(
+        &mut self,
+        render_resources: &RenderResources,
+        gpu_uploads: &mut GpuUploads,
+        texture_id: HandleId,
+        texture: &Texture,
+    ) {
+        match GpuTexture::new(render_resources, gpu_uploads, texture) {
+            Ok(uploading_texture) => {
+                self.uploading.insert(texture_id, uploading_texture);
+            }
+            Err(err) => {
+                error!("Failed to upload texture {:?}: {:?}", texture_id, err);
+                self.remove(texture_id);
+            }
+        }
+    }
+
+    fn remove(&mut self, texture_id: HandleId) {
+        self.textures.remove(&texture_id);
+        self.uploading.remove(&texture_id);
+    }
+
+    /// Makes the textures whose upload is complete available
+    fn finish_uploads(&mut self, gpu_uploads: &GpuUploads) {
+        if let Some((upload_id, _)) = &self.uploading_white {
+            if gpu_uploads.is_complete(*upload_id) {
+                self.white = self.uploading_white.take().map(|(_, white)| white);
+            }
+        }
+
+        self.uploaded = self
+            .uploading
+            .iter()
+            .filter(|(_, (upload_id, _))| gpu_uploads.is_complete(*upload_id))
+            .map(|(&texture_id, _)| texture_id)
+            .collect();
+        for texture_id in &self.uploaded {
+            let (_, gpu_texture) = self.uploading.remove(texture_id).unwrap();
+            self.textures.insert(*texture_id, gpu_texture);
+        }
+    }
+}
+
+/// Uploads created and modified `Texture` assets and releases removed ones. Textures that were
+/// loaded before the `RenderResources` existed are uploaded once they do.
+pub(crate) fn upload_textures(
+    mut texture_events: EventReader<AssetEvent<Texture>>,
+    mut uploaded_existing_textures: Local<bool>,
+    render_resources: Option<Res<RenderResources>>,
+    textures: Res<Assets<Texture>>,
+    mut gpu_uploads: ResMut<GpuUploads>,
+    mut gpu_textures: ResMut<GpuTextures>,
+) {
+    let render_resources = match render_resources {
+        Some(render_resources) => render_resources,
+        None => return,
+    };
+
+    gpu_textures.finish_uploads(&gpu_uploads);
+
+    if !*uploaded_existing_textures {
+        match GpuTexture::white(&render_resources, &mut gpu_uploads) {
+            Ok(white) => gpu_textures.uploading_white = Some(white),
+            Err(err) => error!("Failed to upload the default white texture: {:?}", err),
+        }
+        for (texture_id, texture) in textures.iter() {
+            gpu_textures.upload(&render_resources, &mut gpu_uploads, texture_id, texture);
+        }
+        *uploaded_existing_textures = true;
+
+        // Pending events are about the textures that were just uploaded as they are now
+        texture_events.iter().for_each(drop);
+        return;
+    }
+
+    for event in texture_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                if let Some(texture) = textures.get(handle) {
+                    gpu_textures.upload(&render_resources, &mut gpu_uploads, handle.id, texture);
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                gpu_textures.remove(handle.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mip_levels() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(256, 256), 9);
+        assert_eq!(mip_level_count(300, 20), 9);
+
+        let mips = generate_mips(&[0, 100, 200, 100, 100, 100, 100, 100], 2, 2, 2, false);
+        assert_eq!(mips, vec![vec![100, 100]]);
+
+        // Odd sizes, down to 1x1
+        let mips = generate_mips(&[255; 5 * 3], 5, 3, 1, false);
+        assert_eq!(
+            mips.iter().map(|mip| mip.len()).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert_eq!(mips.len() as u32 + 1, mip_level_count(5, 3));
+    }
+
+    #[test]
+    fn srgb_mips_average_in_linear_space() {
+        let black_and_white = [0, 0, 0, 255, 255, 255, 255, 255];
+        let linear = generate_mips(&black_and_white, 2, 1, 4, false);
+        let srgb = generate_mips(&black_and_white, 2, 1, 4, true);
+
+        assert_eq!(linear, vec![vec![128, 128, 128, 255]]);
+        // Half the light is brighter than half the sRGB value, alpha stays linear
+        assert_eq!(srgb, vec![vec![188, 188, 188, 255]]);
+    }
+
+    #[test]
+    fn linear_and_srgb_formats() {
+        assert_eq!(
+            rafx_format(TextureFormat::Rgba8UnormSrgb),
+            Some(RafxFormat::R8G8B8A8_SRGB)
+        );
+        assert_eq!(
+            rafx_format(TextureFormat::Rgba8Unorm),
+            Some(RafxFormat::R8G8B8A8_UNORM)
+        );
+    }
+}
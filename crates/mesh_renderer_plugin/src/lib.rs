@@ -1,10 +1,22 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::asset::{AssetServer, HandleId, LoadState};
 use bevy::prelude::{
-    AddAsset, Added, Assets, ChangeTrackers, Entity, GlobalTransform, Handle, Or, Plugin, Query,
-    QuerySet, Res, ResMut, Transform, With,
+    AddAsset, Added, AssetEvent, Assets, ChangeTrackers, Commands, Entity, EventReader,
+    GlobalTransform, Handle, Or, Plugin, Query, QuerySet, RemovedComponents, Res, ResMut,
+    Transform, With, Without,
 };
 use bevy::{
-    ecs::{bundle::Bundle, system::IntoSystem},
+    ecs::{
+        bundle::Bundle,
+        reflect::ReflectComponent,
+        schedule::{ParallelSystemDescriptorCoercion, SystemLabel},
+        system::{IntoSystem, SystemParam},
+    },
+    log::warn,
     prelude::Changed,
+    reflect::Reflect,
+    tasks::ComputeTaskPool,
 };
 
 pub use bevy_pbr::prelude::StandardMaterial;
@@ -16,20 +28,45 @@ pub use bevy_render::{
     texture,
 };
 
-use bevy_rafx_plugin::{RenderStage, VisibilityComponent};
-use rafx::{
-    base::slab::DropSlabKey,
-    nodes::GenericRenderNodeHandle,
-    nodes::RenderRegistryBuilder,
-    rafx_visibility::{PolygonSoup, PolygonSoupIndex},
-    visibility::{CullModel, EntityId, VisibilityRegion},
+use bevy_rafx_plugin::{
+    RenderRegistryExt, RenderStage, StaticVisibility, VisibilityComponent, VisibilityObjects,
 };
+use rafx::visibility::{EntityId, VisibilityRegion};
 
 use rafx::render_feature_mod_prelude::*;
 rafx::declare_render_feature!(MeshRenderFeature, MESH_FEATURE_INDEX);
 
+mod cull_model;
 mod extract;
+mod gpu_mesh;
+mod gpu_texture;
+mod material;
 mod mesh_render_node_set;
+mod prepare;
+mod upload;
+mod write;
+
+pub use cull_model::{CullModelCache, CullModelKind, MeshAabb};
+use extract::ExtractedMaterials;
+pub use extract::{ExtractedMaterial, ExtractedMesh, ExtractedMeshes};
+pub use gpu_mesh::{
+    mesh_vertices, vertex_semantic_errors, GpuMesh, GpuMeshes, MeshVertex, VertexAttributeSemantic,
+    VertexSemanticError, MESH_VERTEX_LAYOUT, VERTEX_ATTRIBUTE_SEMANTICS,
+};
+pub use gpu_texture::{generate_mips, rafx_format, rafx_sampler_def, GpuTexture, GpuTextures};
+pub use material::{
+    GpuMaterial, GpuMaterials, MaterialPassKind, MaterialPasses, MaterialTextureBinding,
+    MaterialUniform, RafxMaterial, RafxMaterialExt, RafxMaterialPass,
+    MATERIAL_DESCRIPTOR_SET_INDEX, MATERIAL_UNIFORM_BINDING, PER_OBJECT_DESCRIPTOR_SET_INDEX,
+    PER_VIEW_DESCRIPTOR_SET_INDEX,
+};
+use mesh_render_node_set::MeshRenderNodeHandles;
+pub use mesh_render_node_set::{MeshRenderNode, MeshRenderNodeHandle, MeshRenderNodeSet};
+pub use prepare::{
+    PerObjectUniform, PerViewUniform, PreparedMesh, PreparedMeshView, PreparedMeshes,
+};
+pub use upload::{GpuUploads, UploadId};
+pub use write::MeshCommandWriter;
 
 #[derive(Bundle, Default)]
 pub struct PbrBundle {
@@ -41,117 +78,1211 @@ pub struct PbrBundle {
     pub visibility_component: VisibilityComponent,
 }
 
+/// Marks a mesh as alpha blended, like glTF materials with `alphaMode: BLEND`. Meshes are
+/// blended as well when `RafxMaterial::is_transparent` says so, e.g. for a `StandardMaterial` with
+/// a base color alpha below 1.
+#[derive(Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct AlphaBlend;
+
+/// Systems that systems added by `RafxMaterialExt::add_rafx_material` are ordered against
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+pub enum MeshRendererSystem {
+    UpdateVisibility,
+    PollUploads,
+    UploadMeshes,
+    UploadTextures,
+    ExtractMeshes,
+}
+
 #[derive(Default)]
-pub struct MeshRendererPlugin {}
+pub struct MeshRendererPlugin {
+    /// Used for mesh entities without a `CullModelKind` component
+    pub cull_model_kind: CullModelKind,
+}
+
+/// The `CullModelKind` of mesh entities without a `CullModelKind` component
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultCullModelKind(pub CullModelKind);
 
 impl Plugin for MeshRendererPlugin {
     fn build(&self, app: &mut bevy::prelude::AppBuilder) {
         app.register_type::<VisibilityComponent>()
+            .register_type::<StaticVisibility>()
+            .register_type::<AlphaBlend>()
+            .register_type::<MeshAabb>()
             .add_asset::<Mesh>()
-            .add_asset::<StandardMaterial>()
-            .add_startup_system(setup.system())
-            .add_system_to_stage(RenderStage::Visibility, mesh_update_visibility.system())
-            .add_system_to_stage(RenderStage::Extract, mesh_extract.system());
+            .add_asset::<texture::Texture>()
+            .add_render_feature::<MeshRenderFeature>()
+            .insert_resource(DefaultCullModelKind(self.cull_model_kind))
+            .init_resource::<CullModelCache>()
+            .init_resource::<MeshRenderNodeSet>()
+            .init_resource::<MeshRenderNodeHandles>()
+            .init_resource::<PendingVisibilityObjects>()
+            .init_resource::<StaticVisibilityObjects>()
+            .init_resource::<ExtractedMeshes>()
+            .init_resource::<PreparedMeshes>()
+            .init_resource::<GpuUploads>()
+            .init_resource::<GpuMeshes>()
+            .init_resource::<ExtractedMaterials>()
+            .init_resource::<GpuTextures>()
+            .add_system_to_stage(
+                RenderStage::Visibility,
+                mesh_update_visibility
+                    .system()
+                    .label(MeshRendererSystem::UpdateVisibility),
+            )
+            .add_system_to_stage(
+                RenderStage::Visibility,
+                mesh_update_visibility_transforms
+                    .system()
+                    .after(MeshRendererSystem::UpdateVisibility),
+            )
+            .add_system_to_stage(
+                RenderStage::Visibility,
+                mesh_render_node_set::release_mesh_render_nodes.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::PreExtract,
+                upload::poll_gpu_uploads
+                    .system()
+                    .label(MeshRendererSystem::PollUploads),
+            )
+            .add_system_to_stage(
+                RenderStage::PreExtract,
+                gpu_mesh::upload_meshes
+                    .system()
+                    .label(MeshRendererSystem::UploadMeshes)
+                    .after(MeshRendererSystem::PollUploads),
+            )
+            .add_system_to_stage(
+                RenderStage::PreExtract,
+                gpu_texture::upload_textures
+                    .system()
+                    .label(MeshRendererSystem::UploadTextures)
+                    .after(MeshRendererSystem::PollUploads),
+            )
+            .add_system_to_stage(
+                RenderStage::PreExtract,
+                upload::submit_gpu_uploads
+                    .system()
+                    .after(MeshRendererSystem::UploadMeshes)
+                    .after(MeshRendererSystem::UploadTextures),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                extract::mesh_extract
+                    .system()
+                    .label(MeshRendererSystem::ExtractMeshes),
+            )
+            .add_system_to_stage(RenderStage::Prepare, prepare::mesh_prepare.system())
+            .add_rafx_material::<StandardMaterial>();
     }
 }
 
-fn setup(mut render_registry_builder_resource: ResMut<Option<RenderRegistryBuilder>>) {
-    let render_registry_builder = render_registry_builder_resource
-        .take()
-        .unwrap()
-        .register_feature::<MeshRenderFeature>();
-    render_registry_builder_resource.replace(render_registry_builder);
+/// Entities whose mesh wasn't loaded yet when they needed a visibility object, with the mesh
+/// they wait for. They are registered when the `AssetEvent::Created` of their mesh arrives, and
+/// forgotten when they are despawned, lose their mesh or the mesh fails to load.
+/// Entities that already have a visibility object keep the old cull model until then.
+#[derive(Default)]
+struct PendingVisibilityObjects(HashMap<Entity, HandleId>);
+
+/// Mesh entities whose visibility object is registered with the static acceleration structure
+#[derive(Default)]
+struct StaticVisibilityObjects(HashSet<Entity>);
+
+/// Components whose removal changes the visibility object of a mesh entity
+#[derive(SystemParam)]
+struct RemovedMeshComponents<'a> {
+    meshes: RemovedComponents<'a, Handle<Mesh>>,
+    visibility_components: RemovedComponents<'a, VisibilityComponent>,
+    cull_model_kinds: RemovedComponents<'a, CullModelKind>,
+    mesh_aabbs: RemovedComponents<'a, MeshAabb>,
+    static_visibilities: RemovedComponents<'a, StaticVisibility>,
 }
 
+/// Registers visibility objects for mesh entities and keeps their cull models in sync with
+/// their mesh. Transforms are updated by `mesh_update_visibility_transforms`.
+#[allow(clippy::too_many_arguments)]
 fn mesh_update_visibility(
     mut query: Query<(
         Entity,
         &Handle<Mesh>,
-        &Transform,
+        &GlobalTransform,
         &mut VisibilityComponent,
         ChangeTrackers<Handle<Mesh>>,
-        ChangeTrackers<Transform>,
+        ChangeTrackers<GlobalTransform>,
+        Option<&CullModelKind>,
+        Option<ChangeTrackers<CullModelKind>>,
+        Option<&MeshAabb>,
+        Option<ChangeTrackers<MeshAabb>>,
+        Option<&StaticVisibility>,
+        Option<ChangeTrackers<StaticVisibility>>,
     )>,
+    mut commands: Commands,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    removed_components: RemovedMeshComponents,
+    mut pending_visibility_objects: ResMut<PendingVisibilityObjects>,
+    mut static_visibility_objects: ResMut<StaticVisibilityObjects>,
     visibility_region: Res<VisibilityRegion>,
+    mut visibility_objects: ResMut<VisibilityObjects>,
+    mut cull_model_cache: ResMut<CullModelCache>,
+    mut mesh_render_nodes: ResMut<MeshRenderNodeSet>,
+    mut mesh_render_node_handles: ResMut<MeshRenderNodeHandles>,
+    default_cull_model_kind: Res<DefaultCullModelKind>,
     meshes: Res<Assets<Mesh>>,
+    asset_server: Option<Res<AssetServer>>,
 ) {
+    for entity in removed_components
+        .meshes
+        .iter()
+        .chain(removed_components.visibility_components.iter())
+    {
+        pending_visibility_objects.0.remove(&entity);
+        static_visibility_objects.0.remove(&entity);
+    }
+    // Entities that lost their CullModelKind or MeshAabb fall back to the defaults
+    let removed_cull_model_settings = removed_components
+        .cull_model_kinds
+        .iter()
+        .chain(removed_components.mesh_aabbs.iter())
+        .collect::<HashSet<_>>();
+    let removed_static_visibilities = removed_components
+        .static_visibilities
+        .iter()
+        .collect::<HashSet<_>>();
+    if let Some(asset_server) = asset_server {
+        pending_visibility_objects.0.retain(|entity, mesh_id| {
+            let failed = asset_server.get_load_state(*mesh_id) == LoadState::Failed;
+            if failed {
+                warn!(
+                    "The mesh of entity {:?} failed to load, it won't be visible",
+                    entity
+                );
+            }
+            !failed
+        });
+    }
+
+    let mut created_meshes = HashSet::new();
+    let mut modified_meshes = HashSet::new();
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Created { handle } => {
+                created_meshes.insert(handle.id);
+            }
+            AssetEvent::Modified { handle } => {
+                cull_model_cache.invalidate(handle.id);
+                modified_meshes.insert(handle.id);
+            }
+            AssetEvent::Removed { handle } => {
+                cull_model_cache.invalidate(handle.id);
+            }
+        }
+    }
+
+    let mut mesh_visibility = MeshVisibility {
+        visibility_region: &visibility_region,
+        visibility_objects: &mut visibility_objects,
+        cull_model_cache: &mut cull_model_cache,
+        mesh_render_nodes: &mut mesh_render_nodes,
+        mesh_render_node_handles: &mut mesh_render_node_handles,
+        static_visibility_objects: &mut static_visibility_objects,
+        meshes: &meshes,
+    };
+
     query.for_each_mut(
         |(
             entity,
             mesh_handle,
-            transform,
+            global_transform,
             mut visibility_component,
             change_trackers_mesh_handle,
-            change_trackers_transform,
+            change_trackers_global_transform,
+            cull_model_kind,
+            change_trackers_cull_model_kind,
+            mesh_aabb,
+            change_trackers_mesh_aabb,
+            static_visibility,
+            change_trackers_static_visibility,
         )| {
-            // New VisibilityComponent
-            if visibility_component.is_added() {
-                let mesh = meshes.get(mesh_handle).unwrap();
+            // Static objects can't be moved, so they are replaced by a dynamic object
+            let moved_static_object = static_visibility.is_some()
+                && change_trackers_global_transform.is_changed()
+                && mesh_visibility.is_static(entity);
+            if moved_static_object {
+                warn!(
+                    "Entity {:?} has StaticVisibility but moved, it is culled as a dynamic object from now on",
+                    entity
+                );
+                commands.entity(entity).remove::<StaticVisibility>();
+                mesh_visibility.release_visibility_object(entity, &mut visibility_component);
+            }
 
-                let cull_model = mesh_to_cull_model(mesh);
+            // StaticVisibility was added or removed after the object was registered, so it moves
+            // between the static and dynamic objects of the VisibilityRegion
+            let is_static = static_visibility.is_some() && !moved_static_object;
+            let migrated_object = visibility_component.handle.is_some()
+                && (change_trackers_static_visibility
+                    .map_or(false, |change_trackers| change_trackers.is_added())
+                    || removed_static_visibilities.contains(&entity))
+                && is_static != mesh_visibility.is_static(entity);
+            if migrated_object {
+                mesh_visibility.release_visibility_object(entity, &mut visibility_component);
+            }
 
-                let handle =
-                    visibility_region.register_dynamic_object(EntityId::from(entity), cull_model);
+            // New VisibilityComponent, different mesh, the mesh itself or its cull model changed
+            if moved_static_object
+                || migrated_object
+                || visibility_component.is_added()
+                || change_trackers_mesh_handle.is_changed()
+                || modified_meshes.contains(&mesh_handle.id)
+                || change_trackers_cull_model_kind
+                    .map_or(false, |change_trackers| change_trackers.is_changed())
+                || change_trackers_mesh_aabb
+                    .map_or(false, |change_trackers| change_trackers.is_changed())
+                || removed_cull_model_settings.contains(&entity)
+            {
+                if !mesh_visibility.update_visibility_object(
+                    entity,
+                    mesh_handle,
+                    global_transform,
+                    cull_model_kind
+                        .copied()
+                        .unwrap_or(default_cull_model_kind.0),
+                    mesh_aabb,
+                    is_static,
+                    &mut visibility_component,
+                ) {
+                    pending_visibility_objects
+                        .0
+                        .insert(entity, mesh_handle.id);
+                } else {
+                    // Moved on to a loaded mesh while waiting for another one
+                    pending_visibility_objects.0.remove(&entity);
+                }
+            }
+        },
+    );
 
-                handle.set_transform(transform.translation, transform.rotation, transform.scale);
+    let loaded_entities = pending_visibility_objects
+        .0
+        .iter()
+        .filter(|(_, mesh_id)| created_meshes.contains(mesh_id))
+        .map(|(&entity, &mesh_id)| (entity, mesh_id))
+        .collect::<Vec<_>>();
+    for (entity, mesh_id) in loaded_entities {
+        if let Ok((
+            _,
+            mesh_handle,
+            global_transform,
+            mut visibility_component,
+            _,
+            _,
+            cull_model_kind,
+            _,
+            mesh_aabb,
+            _,
+            static_visibility,
+            _,
+        )) = query.get_mut(entity)
+        {
+            // Removed again in the same frame, the next Created event brings it back
+            if mesh_handle.id == mesh_id
+                && mesh_visibility.update_visibility_object(
+                    entity,
+                    mesh_handle,
+                    global_transform,
+                    cull_model_kind
+                        .copied()
+                        .unwrap_or(default_cull_model_kind.0),
+                    mesh_aabb,
+                    static_visibility.is_some(),
+                    &mut visibility_component,
+                )
+            {
+                pending_visibility_objects.0.remove(&entity);
+            }
+        } else {
+            pending_visibility_objects.0.remove(&entity);
+        }
+    }
+}
 
-                // TODO
-                // handle.add_feature(MeshRenderNodeHandle)
+/// Number of visibility objects whose transforms are submitted together by one task
+pub const VISIBILITY_TRANSFORM_BATCH_SIZE: usize = 1024;
 
-                visibility_component.handle.replace(handle);
-            } else {
-                if change_trackers_mesh_handle.is_changed() {
-                    let mesh = meshes.get(mesh_handle).unwrap();
+/// Moves the visibility objects of mesh entities whose `GlobalTransform` changed, in parallel
+/// batches of `VISIBILITY_TRANSFORM_BATCH_SIZE` entities.
+/// Runs in `RenderStage::Visibility`, after transforms are propagated in `CoreStage::PostUpdate`,
+/// so children move along with their ancestors, and after `mesh_update_visibility`, so objects
+/// that were replaced this frame get moved as well.
+pub fn mesh_update_visibility_transforms(
+    mut query: Query<
+        (&GlobalTransform, &VisibilityComponent),
+        (Changed<GlobalTransform>, Without<StaticVisibility>),
+    >,
+    compute_task_pool: Res<ComputeTaskPool>,
+) {
+    // VisibilityObjectArc sends transform changes to the VisibilityRegion through a channel,
+    // so batches can be submitted from any thread
+    query.par_for_each_mut(
+        &compute_task_pool,
+        VISIBILITY_TRANSFORM_BATCH_SIZE,
+        |(global_transform, visibility_component)| {
+            if let Some(handle) = visibility_component.handle.as_ref() {
+                handle.set_transform(
+                    global_transform.translation,
+                    global_transform.rotation,
+                    global_transform.scale,
+                );
+            }
+        },
+    );
+}
 
-                    let cull_model = mesh_to_cull_model(mesh);
+/// Everything needed to register visibility objects for mesh entities
+struct MeshVisibility<'a> {
+    visibility_region: &'a VisibilityRegion,
+    visibility_objects: &'a mut VisibilityObjects,
+    cull_model_cache: &'a mut CullModelCache,
+    mesh_render_nodes: &'a mut MeshRenderNodeSet,
+    mesh_render_node_handles: &'a mut MeshRenderNodeHandles,
+    static_visibility_objects: &'a mut StaticVisibilityObjects,
+    meshes: &'a Assets<Mesh>,
+}
 
-                    // TODO cannot currently update CullModel
-                    // visibility_component
-                    //     .handle
-                    //     .unwrap()
-                    //     .set_cull_model(Some(cull_model));
+impl<'a> MeshVisibility<'a> {
+    /// Rebuilds the cull model of the entity's visibility object, keeping its features and
+    /// identity, or registers a new object if it doesn't have one yet.
+    /// Returns false if the mesh isn't loaded.
+    #[allow(clippy::too_many_arguments)]
+    fn update_visibility_object(
+        &mut self,
+        entity: Entity,
+        mesh_handle: &Handle<Mesh>,
+        global_transform: &GlobalTransform,
+        cull_model_kind: CullModelKind,
+        mesh_aabb: Option<&MeshAabb>,
+        is_static: bool,
+        visibility_component: &mut VisibilityComponent,
+    ) -> bool {
+        let mesh = match self.meshes.get(mesh_handle) {
+            Some(mesh) => mesh,
+            None => return false,
+        };
 
-                    let handle = visibility_region
-                        .register_dynamic_object(EntityId::from(entity), cull_model);
+        let cull_model =
+            self.cull_model_cache
+                .get_or_build(mesh_handle, mesh, cull_model_kind, mesh_aabb);
 
-                    handle.set_transform(
-                        transform.translation,
-                        transform.rotation,
-                        transform.scale,
-                    );
+        if let Some(handle) = visibility_component.handle.as_ref() {
+            handle.set_cull_model(Some(cull_model));
+            return true;
+        }
 
-                    visibility_component.handle.replace(handle);
-                }
-                if change_trackers_transform.is_changed() {
-                    visibility_component.handle.as_ref().unwrap().set_transform(
-                        transform.translation,
-                        transform.rotation,
-                        transform.scale,
-                    );
-                }
-            }
-        },
-    );
-}
+        let handle = if is_static {
+            self.visibility_region
+                .register_static_object(EntityId::from(entity), cull_model)
+        } else {
+            self.visibility_region
+                .register_dynamic_object(EntityId::from(entity), cull_model)
+        };
+
+        handle.set_transform(
+            global_transform.translation,
+            global_transform.rotation,
+            global_transform.scale,
+        );
+
+        let mesh_render_node = self
+            .mesh_render_node_handles
+            .get_or_register(entity, self.mesh_render_nodes);
+        handle.add_feature(mesh_render_node.as_raw_generic_handle());
+
+        if is_static {
+            self.static_visibility_objects.0.insert(entity);
+        } else {
+            self.static_visibility_objects.0.remove(&entity);
+        }
+        self.visibility_objects.insert(entity, handle.clone());
+        visibility_component.handle.replace(handle);
 
-fn mesh_to_cull_model(mesh: &Mesh) -> CullModel {
-    let vertex_positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap() {
-        VertexAttributeValues::Float32x3(positions) => positions,
-        _ => panic!(),
+        true
     }
-    .iter()
-    .map(|&floats| floats.into())
-    .collect();
 
-    let index = match mesh.indices().unwrap() {
-        mesh::Indices::U16(u16) => PolygonSoupIndex::Indexed16(u16.clone()),
-        mesh::Indices::U32(u32) => PolygonSoupIndex::Indexed32(u32.clone()),
-    };
+    /// Whether the entity's visibility object is a static object
+    fn is_static(&self, entity: Entity) -> bool {
+        self.static_visibility_objects.0.contains(&entity)
+    }
 
-    CullModel::mesh(PolygonSoup {
-        vertex_positions,
-        index,
-    })
+    /// Removes the entity's visibility object from the `VisibilityRegion`
+    fn release_visibility_object(
+        &mut self,
+        entity: Entity,
+        visibility_component: &mut VisibilityComponent,
+    ) {
+        self.visibility_objects.remove(entity);
+        self.static_visibility_objects.0.remove(&entity);
+        visibility_component.handle = None;
+    }
 }
 
-fn mesh_extract() {}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::{
+        asset::AssetPlugin,
+        math::Vec3,
+        prelude::{App, AppBuilder, BuildWorldChildren, MinimalPlugins},
+        reflect::TypeUuid,
+        transform::TransformPlugin,
+    };
+    use bevy_rafx_plugin::phases::{
+        depth_prepass_render_phase::DepthPrepassRenderPhase,
+        opaque_render_phase::OpaqueRenderPhase, transparent_render_phase::TransparentRenderPhase,
+    };
+    use bevy_rafx_plugin::{
+        test_util, FramePacketViews, PerspectiveCameraBundle, PreparedRenderFeatures,
+        RenderResources, Renderer,
+    };
+    use rafx::{
+        nodes::{RenderPhase, SubmitNode},
+        rafx_visibility::VisibilityQuery,
+    };
+
+    fn headless_app() -> App {
+        headless_app_with(|_| {})
+    }
+
+    fn headless_app_with(build: impl FnOnce(&mut AppBuilder)) -> App {
+        let mut app = test_util::headless_app_with(|app_builder| {
+            app_builder
+                .add_plugin(AssetPlugin)
+                .add_plugin(TransformPlugin)
+                .add_plugin(MeshRendererPlugin::default());
+            build(app_builder);
+        });
+
+        app.world
+            .spawn()
+            .insert_bundle(PerspectiveCameraBundle::new_3d());
+
+        app
+    }
+
+    fn spawn_cube(app: &mut App, transform: Transform) -> Entity {
+        let mesh = app
+            .world
+            .get_resource_mut::<Assets<Mesh>>()
+            .unwrap()
+            .add(Mesh::from(mesh::shape::Cube { size: 1.0 }));
+
+        app.world
+            .spawn()
+            .insert_bundle(PbrBundle {
+                mesh,
+                transform,
+                global_transform: GlobalTransform::from(transform),
+                ..Default::default()
+            })
+            .id()
+    }
+
+    /// Number of objects in the frustum of the first view of the current frame packet
+    fn visible_object_count(app: &App) -> usize {
+        let frame_packet_views = app.world.get_resource::<FramePacketViews>().unwrap();
+        let mut visibility_query = VisibilityQuery::default();
+        frame_packet_views.0[0]
+            .view_frustum()
+            .query_visibility(&mut visibility_query)
+            .unwrap();
+        visibility_query.objects.len()
+    }
+
+    #[test]
+    fn despawned_entity_is_not_visible() {
+        let mut app = headless_app();
+
+        let despawned = spawn_cube(&mut app, Transform::from_xyz(-1.0, 0.0, -5.0));
+        spawn_cube(&mut app, Transform::from_xyz(1.0, 0.0, -5.0));
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), 2);
+
+        app.world.despawn(despawned);
+        app.update();
+
+        assert!(app
+            .world
+            .get_resource::<VisibilityObjects>()
+            .unwrap()
+            .get(despawned)
+            .is_none());
+        assert!(!app
+            .world
+            .get_resource::<MeshRenderNodeHandles>()
+            .unwrap()
+            .contains(despawned));
+        assert_eq!(visible_object_count(&app), 1);
+    }
+
+    #[test]
+    fn mesh_loaded_after_spawn() {
+        let mut app = headless_app();
+
+        let mesh = app
+            .world
+            .get_resource::<Assets<Mesh>>()
+            .unwrap()
+            .get_handle(HandleId::random::<Mesh>());
+        let entity = app
+            .world
+            .spawn()
+            .insert_bundle(PbrBundle {
+                mesh: mesh.clone(),
+                transform: Transform::from_xyz(0.0, 0.0, -5.0),
+                ..Default::default()
+            })
+            .id();
+
+        // The mesh isn't there yet, the entity waits for it
+        app.update();
+        assert!(app
+            .world
+            .get::<VisibilityComponent>(entity)
+            .unwrap()
+            .handle
+            .is_none());
+
+        app.world
+            .get_resource_mut::<Assets<Mesh>>()
+            .unwrap()
+            .set(mesh, Mesh::from(mesh::shape::Cube { size: 1.0 }));
+        app.update();
+
+        assert!(app
+            .world
+            .get::<VisibilityComponent>(entity)
+            .unwrap()
+            .handle
+            .is_some());
+        assert!(app
+            .world
+            .get_resource::<VisibilityObjects>()
+            .unwrap()
+            .get(entity)
+            .is_some());
+    }
+
+    #[test]
+    fn despawned_pending_entity_is_forgotten() {
+        let mut app = headless_app();
+
+        let mesh = app
+            .world
+            .get_resource::<Assets<Mesh>>()
+            .unwrap()
+            .get_handle(HandleId::random::<Mesh>());
+        let despawned = app
+            .world
+            .spawn()
+            .insert_bundle(PbrBundle {
+                mesh: mesh.clone(),
+                ..Default::default()
+            })
+            .id();
+        let without_mesh = app
+            .world
+            .spawn()
+            .insert_bundle(PbrBundle {
+                mesh,
+                ..Default::default()
+            })
+            .id();
+
+        app.update();
+        let pending_visibility_objects = app
+            .world
+            .get_resource::<PendingVisibilityObjects>()
+            .unwrap();
+        assert!(pending_visibility_objects.0.contains_key(&despawned));
+        assert!(pending_visibility_objects.0.contains_key(&without_mesh));
+
+        app.world.despawn(despawned);
+        app.world.entity_mut(without_mesh).remove::<Handle<Mesh>>();
+        app.update();
+
+        assert!(app
+            .world
+            .get_resource::<PendingVisibilityObjects>()
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn modified_mesh_updates_cull_model() {
+        let mut app = headless_app();
+
+        let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+
+        let mesh = app.world.get::<Handle<Mesh>>(entity).unwrap().clone();
+        *app.world
+            .get_resource_mut::<Assets<Mesh>>()
+            .unwrap()
+            .get_mut(mesh)
+            .unwrap() = Mesh::from(mesh::shape::Box {
+            min_x: 100.0,
+            max_x: 101.0,
+            min_y: 100.0,
+            max_y: 101.0,
+            min_z: 0.0,
+            max_z: 1.0,
+        });
+        app.update();
+
+        // Still the same object, now out of view
+        assert_eq!(
+            app.world.get_resource::<VisibilityObjects>().unwrap().len(),
+            1
+        );
+        assert_eq!(visible_object_count(&app), 0);
+    }
+
+    #[test]
+    fn cull_models_scale_with_unique_meshes() {
+        let mut app = headless_app();
+
+        let meshes = {
+            let mut meshes = app.world.get_resource_mut::<Assets<Mesh>>().unwrap();
+            vec![
+                meshes.add(Mesh::from(mesh::shape::Cube { size: 1.0 })),
+                meshes.add(Mesh::from(mesh::shape::Icosphere::default())),
+            ]
+        };
+        for i in 0..1000 {
+            app.world.spawn().insert_bundle(PbrBundle {
+                mesh: meshes[i % meshes.len()].clone(),
+                transform: Transform::from_xyz(i as f32, 0.0, -5.0),
+                ..Default::default()
+            });
+        }
+
+        app.update();
+
+        // Every entity got a visibility object, but there is one cull model per unique mesh
+        assert_eq!(
+            app.world.get_resource::<VisibilityObjects>().unwrap().len(),
+            1000
+        );
+        let cull_model_cache = app.world.get_resource::<CullModelCache>().unwrap();
+        assert_eq!(cull_model_cache.len(), meshes.len());
+        assert_eq!(cull_model_cache.build_count(), meshes.len());
+
+        // Modifying a mesh rebuilds its cull model once, not once per entity
+        app.world.get_resource_mut::<Assets<Mesh>>().unwrap().set(
+            meshes[0].clone(),
+            Mesh::from(mesh::shape::Cube { size: 2.0 }),
+        );
+        app.update();
+
+        let cull_model_cache = app.world.get_resource::<CullModelCache>().unwrap();
+        assert_eq!(cull_model_cache.len(), meshes.len());
+        assert_eq!(cull_model_cache.build_count(), meshes.len() + 1);
+    }
+
+    #[test]
+    fn cull_model_kinds() {
+        let mut app = headless_app();
+
+        for &cull_model_kind in &[
+            CullModelKind::BoundingSphere,
+            CullModelKind::Aabb,
+            CullModelKind::Quad,
+            CullModelKind::Mesh,
+        ] {
+            let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+            app.world.entity_mut(entity).insert(cull_model_kind);
+        }
+        // Known bounds are used instead of the mesh's positions, and aren't shared with other
+        // entities using the same mesh
+        let out_of_view_aabb = MeshAabb {
+            min: Vec3::new(100.0, 100.0, 0.0),
+            max: Vec3::new(101.0, 101.0, 1.0),
+        };
+        let out_of_view_entities = [CullModelKind::Aabb, CullModelKind::Quad]
+            .iter()
+            .map(|&cull_model_kind| {
+                let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+                app.world
+                    .entity_mut(entity)
+                    .insert(cull_model_kind)
+                    .insert(out_of_view_aabb);
+                entity
+            })
+            .collect::<Vec<_>>();
+
+        app.update();
+        app.update();
+
+        assert_eq!(visible_object_count(&app), 4);
+
+        // Without them, the entities are culled by the cube's triangles again
+        app.world
+            .entity_mut(out_of_view_entities[0])
+            .remove::<CullModelKind>();
+        app.world
+            .entity_mut(out_of_view_entities[1])
+            .remove::<MeshAabb>();
+        app.update();
+
+        assert_eq!(visible_object_count(&app), 6);
+    }
+
+    #[test]
+    fn removed_component_is_not_visible() {
+        let mut app = headless_app();
+
+        let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+
+        app.world.entity_mut(entity).remove::<VisibilityComponent>();
+        app.update();
+
+        assert!(app
+            .world
+            .get_resource::<VisibilityObjects>()
+            .unwrap()
+            .is_empty());
+        assert!(!app
+            .world
+            .get_resource::<MeshRenderNodeHandles>()
+            .unwrap()
+            .contains(entity));
+        assert_eq!(visible_object_count(&app), 0);
+    }
+
+    #[test]
+    fn children_are_culled_at_their_global_transform() {
+        let mut app = headless_app();
+
+        let mesh = app
+            .world
+            .get_resource_mut::<Assets<Mesh>>()
+            .unwrap()
+            .add(Mesh::from(mesh::shape::Cube { size: 1.0 }));
+
+        // The parent is outside of the frustum, its child inside. With only the local Transform,
+        // the child would be culled at x = 100.
+        let parent = app
+            .world
+            .spawn()
+            .insert_bundle(PbrBundle {
+                mesh: mesh.clone(),
+                transform: Transform::from_xyz(-100.0, 0.0, -5.0),
+                ..Default::default()
+            })
+            .with_children(|parent| {
+                parent.spawn_bundle(PbrBundle {
+                    mesh,
+                    transform: Transform::from_xyz(100.0, 0.0, 0.0),
+                    ..Default::default()
+                });
+            })
+            .id();
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+
+        // Moving only the parent takes the child out of the frustum as well
+        app.world
+            .get_mut::<Transform>(parent)
+            .unwrap()
+            .translation
+            .x = -200.0;
+        app.update();
+        assert_eq!(visible_object_count(&app), 0);
+
+        // And back in
+        app.world
+            .get_mut::<Transform>(parent)
+            .unwrap()
+            .translation
+            .x = -100.0;
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+    }
+
+    #[test]
+    fn moving_many_entities() {
+        let mut app = headless_app();
+
+        let mesh = app
+            .world
+            .get_resource_mut::<Assets<Mesh>>()
+            .unwrap()
+            .add(Mesh::from(mesh::shape::Cube { size: 1.0 }));
+        let entities = (0..10_000)
+            .map(|i| {
+                app.world
+                    .spawn()
+                    .insert_bundle(PbrBundle {
+                        mesh: mesh.clone(),
+                        transform: Transform::from_xyz((i % 2) as f32, 0.0, -5.0),
+                        ..Default::default()
+                    })
+                    .id()
+            })
+            .collect::<Vec<_>>();
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), entities.len());
+
+        // Move every other entity out of the frustum
+        for &entity in entities.iter().step_by(2) {
+            app.world
+                .get_mut::<Transform>(entity)
+                .unwrap()
+                .translation
+                .x = 100.0;
+        }
+
+        app.update();
+        assert_eq!(visible_object_count(&app), entities.len() / 2);
+
+        // Swap which half is out of the frustum, so every object must have been moved
+        for (i, &entity) in entities.iter().enumerate() {
+            app.world
+                .get_mut::<Transform>(entity)
+                .unwrap()
+                .translation
+                .y = if i % 2 == 0 { 0.0 } else { 100.0 };
+        }
+        for &entity in entities.iter().step_by(2) {
+            app.world
+                .get_mut::<Transform>(entity)
+                .unwrap()
+                .translation
+                .x = 0.0;
+        }
+        app.update();
+        assert_eq!(visible_object_count(&app), entities.len() / 2);
+
+        for &entity in entities.iter().skip(1).step_by(2) {
+            app.world
+                .get_mut::<Transform>(entity)
+                .unwrap()
+                .translation
+                .y = 0.0;
+        }
+        app.update();
+        assert_eq!(visible_object_count(&app), entities.len());
+    }
+
+    #[test]
+    fn static_visibility_added_and_removed_later() {
+        let mut app = headless_app();
+
+        let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+
+        app.update();
+        app.update();
+        assert!(!app
+            .world
+            .get_resource::<StaticVisibilityObjects>()
+            .unwrap()
+            .0
+            .contains(&entity));
+
+        app.world.entity_mut(entity).insert(StaticVisibility);
+        app.update();
+
+        assert!(app
+            .world
+            .get_resource::<StaticVisibilityObjects>()
+            .unwrap()
+            .0
+            .contains(&entity));
+        assert_eq!(
+            app.world.get_resource::<VisibilityObjects>().unwrap().len(),
+            1
+        );
+        assert_eq!(visible_object_count(&app), 1);
+
+        app.world.entity_mut(entity).remove::<StaticVisibility>();
+        app.update();
+
+        assert!(!app
+            .world
+            .get_resource::<StaticVisibilityObjects>()
+            .unwrap()
+            .0
+            .contains(&entity));
+        assert_eq!(visible_object_count(&app), 1);
+
+        // Dynamic again, so moving it is picked up
+        app.world
+            .get_mut::<Transform>(entity)
+            .unwrap()
+            .translation
+            .x = 100.0;
+        app.update();
+        assert_eq!(visible_object_count(&app), 0);
+    }
+
+    #[test]
+    fn moved_static_entity_becomes_dynamic() {
+        let mut app = headless_app();
+
+        let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+        app.world.entity_mut(entity).insert(StaticVisibility);
+
+        app.update();
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+
+        // Not moved, stays static
+        app.update();
+        assert!(app.world.get::<StaticVisibility>(entity).is_some());
+
+        app.world
+            .get_mut::<Transform>(entity)
+            .unwrap()
+            .translation
+            .x = 100.0;
+        app.update();
+
+        assert!(app.world.get::<StaticVisibility>(entity).is_none());
+        assert_eq!(
+            app.world.get_resource::<VisibilityObjects>().unwrap().len(),
+            1
+        );
+        assert_eq!(visible_object_count(&app), 0);
+
+        // From now on it moves like any other entity
+        app.world
+            .get_mut::<Transform>(entity)
+            .unwrap()
+            .translation
+            .x = 0.0;
+        app.update();
+        assert_eq!(visible_object_count(&app), 1);
+    }
+
+    #[test]
+    fn visible_meshes_are_extracted() {
+        let mut app = headless_app();
+
+        spawn_cube(&mut app, Transform::from_xyz(-1.0, 0.0, -5.0));
+        spawn_cube(&mut app, Transform::from_xyz(100.0, 0.0, -5.0));
+        let transparent = spawn_cube(&mut app, Transform::from_xyz(1.0, 0.0, -5.0));
+        app.world.entity_mut(transparent).insert(AlphaBlend);
+
+        // Visibility objects and views are created in the first frame, the second frame's frame
+        // packet contains them
+        app.update();
+        app.update();
+
+        let extracted_meshes = app.world.get_resource::<ExtractedMeshes>().unwrap();
+        assert_eq!(extracted_meshes.frame_nodes.len(), 2);
+        assert_eq!(
+            extracted_meshes
+                .frame_nodes
+                .iter()
+                .flatten()
+                .filter(|extracted_mesh| extracted_mesh.is_transparent)
+                .map(|extracted_mesh| extracted_mesh.entity)
+                .collect::<Vec<_>>(),
+            vec![transparent]
+        );
+
+        assert_eq!(extracted_meshes.view_submit_nodes.len(), 1);
+        let view_submit_nodes = &extracted_meshes.view_submit_nodes[0];
+        for &(render_phase_index, count) in &[
+            (DepthPrepassRenderPhase::render_phase_index(), 1),
+            (OpaqueRenderPhase::render_phase_index(), 1),
+            (TransparentRenderPhase::render_phase_index(), 1),
+        ] {
+            assert_eq!(
+                view_submit_nodes.submit_nodes(render_phase_index).len(),
+                count
+            );
+        }
+    }
+
+    #[derive(TypeUuid)]
+    #[uuid = "fefe311d-e0c2-4c03-bd12-83d1284b3c74"]
+    struct TestMaterial {
+        transparent: bool,
+    }
+
+    impl RafxMaterial for TestMaterial {
+        type Uniform = ();
+
+        const SHADER_PACKAGES: &'static [&'static str] = &[
+            "test.vert.cookedshaderpackage",
+            "test.frag.cookedshaderpackage",
+        ];
+
+        fn uniform(&self) {}
+
+        fn is_transparent(&self) -> bool {
+            self.transparent
+        }
+    }
+
+    #[test]
+    fn extracted_meshes_are_prepared_in_phase_order() {
+        let mut app = headless_app();
+
+        let entities = [-5.0, -15.0, -10.0]
+            .iter()
+            .map(|&z| {
+                let entity = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, z));
+                app.world.entity_mut(entity).insert(AlphaBlend);
+                entity
+            })
+            .collect::<Vec<_>>();
+        let opaque = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -20.0));
+
+        app.update();
+        app.update();
+
+        let extracted_meshes = app.world.get_resource::<ExtractedMeshes>().unwrap();
+        let prepared_meshes = app.world.get_resource::<PreparedMeshes>().unwrap();
+        let submit_node_entities = |submit_nodes: &[SubmitNode]| {
+            submit_nodes
+                .iter()
+                .map(|submit_node| {
+                    extracted_meshes.frame_nodes[submit_node.submit_node_id() as usize]
+                        .as_ref()
+                        .unwrap()
+                        .entity
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(prepared_meshes.views.len(), 1);
+        let prepared_mesh_view = &prepared_meshes.views[0];
+        // Back-to-front
+        assert_eq!(
+            submit_node_entities(
+                prepared_mesh_view.submit_nodes(TransparentRenderPhase::render_phase_index())
+            ),
+            vec![entities[1], entities[2], entities[0]]
+        );
+        assert_eq!(
+            submit_node_entities(
+                prepared_mesh_view.submit_nodes(OpaqueRenderPhase::render_phase_index())
+            ),
+            vec![opaque]
+        );
+
+        assert_eq!(
+            prepared_meshes.frame_nodes.len(),
+            extracted_meshes.frame_nodes.len()
+        );
+    }
+
+    #[test]
+    fn meshes_are_prepared_on_the_headless_device() {
+        let mut app = headless_app();
+        spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+
+        // The meshes are uploaded in the second frame, the upload is polled in the third
+        app.update();
+        app.update();
+        app.update();
+
+        assert!(app.world.get_resource::<RenderResources>().is_some());
+        assert_eq!(app.world.get_resource::<GpuMeshes>().unwrap().len(), 1);
+        let prepared_meshes = app.world.get_resource::<PreparedMeshes>().unwrap();
+        assert_eq!(prepared_meshes.frame_nodes.len(), 1);
+        assert!(prepared_meshes.frame_nodes[0].is_some());
+        assert!(prepared_meshes.views[0].per_view_descriptor_set.is_some());
+
+        // Frames are rendered from the first one on, whether or not there is anything to draw
+        app.update();
+        let renderer = app.world.get_resource::<Renderer>().unwrap();
+        assert_eq!(renderer.frame_count(), 4);
+        assert!(app
+            .world
+            .get_resource::<PreparedRenderFeatures>()
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn meshes_with_custom_materials_are_extracted() {
+        let mut app = headless_app_with(|app_builder| {
+            app_builder.add_rafx_material::<TestMaterial>();
+        });
+
+        let (opaque_material, transparent_material) = {
+            let mut materials = app
+                .world
+                .get_resource_mut::<Assets<TestMaterial>>()
+                .unwrap();
+            (
+                materials.add(TestMaterial { transparent: false }),
+                materials.add(TestMaterial { transparent: true }),
+            )
+        };
+        let opaque = spawn_cube(&mut app, Transform::from_xyz(-1.0, 0.0, -5.0));
+        app.world.entity_mut(opaque).insert(opaque_material.clone());
+        let transparent = spawn_cube(&mut app, Transform::from_xyz(1.0, 0.0, -5.0));
+        app.world
+            .entity_mut(transparent)
+            .insert(transparent_material.clone());
+        let standard = spawn_cube(&mut app, Transform::from_xyz(0.0, 0.0, -5.0));
+
+        app.update();
+        app.update();
+
+        let extracted_meshes = app.world.get_resource::<ExtractedMeshes>().unwrap();
+        let extracted_material = |entity| {
+            let extracted_mesh = extracted_meshes
+                .frame_nodes
+                .iter()
+                .flatten()
+                .find(|extracted_mesh| extracted_mesh.entity == entity)
+                .unwrap();
+            let material = extracted_mesh.material.as_ref().unwrap();
+            // TestMaterial has no cooked shaders, so it has no material pass
+            assert!(material.material_passes.is_none());
+            assert!(material.descriptor_set.is_none());
+            (material.material.id, extracted_mesh.is_transparent)
+        };
+        // TestMaterial was registered after the StandardMaterial all cubes have
+        assert_eq!(extracted_material(opaque), (opaque_material.id, false));
+        assert_eq!(
+            extracted_material(transparent),
+            (transparent_material.id, true)
+        );
+        assert_eq!(
+            extracted_material(standard),
+            (Handle::<StandardMaterial>::default().id, false)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "MeshRendererPlugin must be added first")]
+    fn register_material_without_plugin() {
+        App::build()
+            .add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin)
+            .add_rafx_material::<TestMaterial>();
+    }
+}
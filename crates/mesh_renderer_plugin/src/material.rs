@@ -0,0 +1,688 @@
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+};
+
+use bevy::{
+    asset::{Asset, HandleId},
+    ecs::schedule::ParallelSystemDescriptorCoercion,
+    log::error,
+    prelude::{
+        AddAsset, AppBuilder, AssetEvent, Assets, Commands, EventReader, Handle, IntoSystem, Local,
+        Res, ResMut,
+    },
+};
+use bevy_rafx_plugin::{
+    create_material_pass,
+    phases::{
+        depth_prepass_render_phase::DepthPrepassRenderPhase,
+        opaque_render_phase::OpaqueRenderPhase, transparent_render_phase::TransparentRenderPhase,
+    },
+    RenderResources, RenderStage,
+};
+use rafx::{
+    api::{
+        RafxBlendState, RafxColorFlags, RafxCompareOp, RafxCullMode, RafxDepthState, RafxError,
+        RafxRasterizerState, RafxResult,
+    },
+    framework::{DescriptorSetArc, FixedFunctionState, MaterialPassResource, ResourceArc},
+    nodes::{RenderPhase, RenderPhaseIndex},
+};
+
+use crate::{
+    extract, texture::Texture, ExtractedMeshes, GpuTextures, MeshRendererSystem, StandardMaterial,
+};
+
+/// Descriptor set of the mesh shaders with the view's `PerViewUniform`, see `shader.vert`
+pub const PER_VIEW_DESCRIPTOR_SET_INDEX: usize = 0;
+/// Descriptor set of the mesh shaders with the mesh's `PerObjectUniform`, see `shader.vert`
+pub const PER_OBJECT_DESCRIPTOR_SET_INDEX: usize = 1;
+/// Descriptor set of the mesh shaders with the material's uniform and textures, see `shader.frag`
+pub const MATERIAL_DESCRIPTOR_SET_INDEX: usize = 2;
+pub const MATERIAL_UNIFORM_BINDING: u32 = 0;
+
+/// Where a texture of a `RafxMaterial` and its sampler are bound in the material descriptor set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaterialTextureBinding {
+    pub texture: u32,
+    pub sampler: u32,
+}
+
+/// A material asset that meshes are drawn with, using its own shaders. Meshes with a
+/// `Handle<M>` are drawn with it once it is registered with `RafxMaterialExt::add_rafx_material`.
+/// Meshes with handles of several registered materials are drawn with the one registered last,
+/// so a custom material takes precedence over the `StandardMaterial` of a `PbrBundle`.
+///
+/// The vertex shader gets `MeshVertex`es, so its inputs can only use the semantics of
+/// `VERTEX_ATTRIBUTE_SEMANTICS`. Meshes without the attributes the shader needs aren't drawn.
+/// Like `shader.vert`, it gets the `PerViewUniform` and `PerObjectUniform` at binding 0 of
+/// descriptor sets `PER_VIEW_DESCRIPTOR_SET_INDEX` and `PER_OBJECT_DESCRIPTOR_SET_INDEX`.
+/// The material's uniform and textures are bound in descriptor set
+/// `MATERIAL_DESCRIPTOR_SET_INDEX`, the uniform at `MATERIAL_UNIFORM_BINDING`.
+pub trait RafxMaterial: Asset {
+    /// Laid out like the uniform block of the shaders, e.g. with `#[repr(C)]` and std140
+    /// padding. Nothing is bound for zero sized types.
+    type Uniform: Copy + 'static;
+
+    /// Cooked shader packages in `COOKED_SHADERS_DIR`, one per stage, the vertex shader first
+    const SHADER_PACKAGES: &'static [&'static str];
+
+    /// Bindings of the textures returned by `textures`, in the same order
+    const TEXTURE_BINDINGS: &'static [MaterialTextureBinding] = &[];
+
+    fn uniform(&self) -> Self::Uniform;
+
+    /// One per `TEXTURE_BINDINGS`. A white texture is bound for None and for textures that
+    /// aren't uploaded (yet).
+    fn textures(&self) -> Vec<Option<Handle<Texture>>> {
+        Vec::new()
+    }
+
+    /// Drawn back-to-front in the `TransparentRenderPhase` instead of in the `OpaqueRenderPhase`
+    fn is_transparent(&self) -> bool {
+        false
+    }
+
+    /// The shader packages of the depth prepass, which only writes depth. Defaults to the
+    /// vertex shader of `SHADER_PACKAGES` without fragment shading.
+    fn depth_prepass_shader_packages() -> &'static [&'static str] {
+        &Self::SHADER_PACKAGES[..Self::SHADER_PACKAGES.len().min(1)]
+    }
+
+    /// How the material's pass of the given kind draws. The depth prepass only writes the depth
+    /// of opaque meshes, so the opaque pass shades each visible fragment once without writing
+    /// depth again. Transparent meshes are alpha blended over them without writing depth, so
+    /// the meshes behind them aren't hidden.
+    fn fixed_function_state(kind: MaterialPassKind) -> FixedFunctionState {
+        let (blend_state, depth_write_enable) = match kind {
+            MaterialPassKind::DepthPrepass => {
+                let mut blend_state = RafxBlendState::default_alpha_disabled();
+                for render_target_blend_state in &mut blend_state.render_target_blend_states {
+                    render_target_blend_state.masks = RafxColorFlags::empty();
+                }
+                (blend_state, true)
+            }
+            MaterialPassKind::Opaque => (RafxBlendState::default_alpha_disabled(), false),
+            MaterialPassKind::Transparent => (RafxBlendState::default_alpha_enabled(), false),
+        };
+
+        FixedFunctionState {
+            blend_state,
+            depth_state: RafxDepthState {
+                depth_test_enable: true,
+                depth_write_enable,
+                depth_compare_op: RafxCompareOp::LessOrEqual,
+                ..Default::default()
+            },
+            rasterizer_state: RafxRasterizerState {
+                cull_mode: RafxCullMode::Back,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The render phases a `RafxMaterial` has a material pass for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialPassKind {
+    /// Depth only, without the material's descriptor set
+    DepthPrepass,
+    Opaque,
+    Transparent,
+}
+
+impl MaterialPassKind {
+    /// The kind of pass that draws in the render phase, None for phases meshes aren't drawn in
+    pub fn from_render_phase_index(render_phase_index: RenderPhaseIndex) -> Option<Self> {
+        if render_phase_index == DepthPrepassRenderPhase::render_phase_index() {
+            Some(MaterialPassKind::DepthPrepass)
+        } else if render_phase_index == OpaqueRenderPhase::render_phase_index() {
+            Some(MaterialPassKind::Opaque)
+        } else if render_phase_index == TransparentRenderPhase::render_phase_index() {
+            Some(MaterialPassKind::Transparent)
+        } else {
+            None
+        }
+    }
+}
+
+/// The material passes of a `RafxMaterial`, one per `MaterialPassKind`. The opaque and transparent
+/// passes are created from the same shaders, so they have the same descriptor set layouts.
+#[derive(Clone)]
+pub struct MaterialPasses {
+    pub depth_prepass: ResourceArc<MaterialPassResource>,
+    pub opaque: ResourceArc<MaterialPassResource>,
+    pub transparent: ResourceArc<MaterialPassResource>,
+}
+
+impl MaterialPasses {
+    pub fn get(&self, kind: MaterialPassKind) -> &ResourceArc<MaterialPassResource> {
+        match kind {
+            MaterialPassKind::DepthPrepass => &self.depth_prepass,
+            MaterialPassKind::Opaque => &self.opaque,
+            MaterialPassKind::Transparent => &self.transparent,
+        }
+    }
+}
+
+/// The uniform data of a `StandardMaterial`, laid out like `MaterialData` in `shader.frag`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct MaterialUniform {
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub reflectance: f32,
+    pub unlit: u32,
+}
+
+impl From<&StandardMaterial> for MaterialUniform {
+    fn from(material: &StandardMaterial) -> Self {
+        MaterialUniform {
+            base_color: material.base_color.as_linear_rgba_f32(),
+            emissive: material.emissive.as_linear_rgba_f32(),
+            metallic: material.metallic,
+            roughness: material.roughness,
+            reflectance: material.reflectance,
+            unlit: material.unlit as u32,
+        }
+    }
+}
+
+impl RafxMaterial for StandardMaterial {
+    type Uniform = MaterialUniform;
+
+    const SHADER_PACKAGES: &'static [&'static str] = &[
+        "shader.vert.cookedshaderpackage",
+        "shader.frag.cookedshaderpackage",
+    ];
+
+    /// Base color, metallic roughness, normal, occlusion and emissive, like in `shader.frag`
+    const TEXTURE_BINDINGS: &'static [MaterialTextureBinding] = &[
+        MaterialTextureBinding {
+            texture: 1,
+            sampler: 2,
+        },
+        MaterialTextureBinding {
+            texture: 3,
+            sampler: 4,
+        },
+        MaterialTextureBinding {
+            texture: 5,
+            sampler: 6,
+        },
+        MaterialTextureBinding {
+            texture: 7,
+            sampler: 8,
+        },
+        MaterialTextureBinding {
+            texture: 9,
+            sampler: 10,
+        },
+    ];
+
+    fn uniform(&self) -> MaterialUniform {
+        MaterialUniform::from(self)
+    }
+
+    fn textures(&self) -> Vec<Option<Handle<Texture>>> {
+        vec![
+            self.base_color_texture.clone(),
+            self.metallic_roughness_texture.clone(),
+            self.normal_map.clone(),
+            self.occlusion_texture.clone(),
+            self.emissive_texture.clone(),
+        ]
+    }
+
+    fn is_transparent(&self) -> bool {
+        self.base_color.a() < 1.0
+    }
+}
+
+/// Registers `RafxMaterial`s with the mesh feature
+pub trait RafxMaterialExt {
+    fn add_rafx_material<M: RafxMaterial>(&mut self) -> &mut Self;
+}
+
+impl RafxMaterialExt for AppBuilder {
+    fn add_rafx_material<M: RafxMaterial>(&mut self) -> &mut Self {
+        if self.world().get_resource::<ExtractedMeshes>().is_none() {
+            panic!(
+                "Cannot register {}, MeshRendererPlugin must be added first",
+                std::any::type_name::<M>()
+            );
+        }
+
+        self.world_mut()
+            .get_resource_mut::<extract::ExtractedMaterials>()
+            .unwrap()
+            .register::<M>();
+
+        self.add_asset::<M>()
+            .init_resource::<GpuMaterials<M>>()
+            .add_system_to_stage(
+                RenderStage::Visibility,
+                create_rafx_material_pass::<M>.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::PreExtract,
+                // Materials bind the uploaded textures
+                prepare_materials::<M>
+                    .system()
+                    .after(MeshRendererSystem::UploadTextures),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                extract::extract_materials::<M>
+                    .system()
+                    .before(MeshRendererSystem::ExtractMeshes),
+            )
+    }
+}
+
+/// The material passes that meshes with a `Handle<M>` are drawn with
+pub struct RafxMaterialPass<M: RafxMaterial> {
+    pub material_passes: MaterialPasses,
+    marker: PhantomData<fn() -> M>,
+}
+
+/// Creates the `RafxMaterialPass` of `M` from its cooked shaders once the `RenderResources`
+/// exist. Only tried once if it fails.
+fn create_rafx_material_pass<M: RafxMaterial>(
+    mut commands: Commands,
+    mut failed: Local<bool>,
+    render_resources: Option<Res<RenderResources>>,
+    rafx_material_pass: Option<Res<RafxMaterialPass<M>>>,
+) {
+    if rafx_material_pass.is_some() || *failed {
+        return;
+    }
+
+    let render_resources = match render_resources {
+        Some(render_resources) => render_resources,
+        None => return,
+    };
+
+    let create = |kind: MaterialPassKind| {
+        let shader_packages = match kind {
+            MaterialPassKind::DepthPrepass => M::depth_prepass_shader_packages(),
+            _ => M::SHADER_PACKAGES,
+        };
+        create_material_pass(
+            &render_resources,
+            &format!("{} {:?}", std::any::type_name::<M>(), kind),
+            shader_packages,
+            M::fixed_function_state(kind),
+        )
+        .map(|material_pass| material_pass.material_pass_resource)
+    };
+
+    let material_passes = create(MaterialPassKind::Opaque).and_then(|opaque| {
+        Ok(MaterialPasses {
+            depth_prepass: create(MaterialPassKind::DepthPrepass)?,
+            opaque,
+            transparent: create(MaterialPassKind::Transparent)?,
+        })
+    });
+    match material_passes {
+        Ok(material_passes) => commands.insert_resource(RafxMaterialPass::<M> {
+            material_passes,
+            marker: PhantomData,
+        }),
+        Err(err) => {
+            error!(
+                "Failed to create the material passes of {}: {:?}",
+                std::any::type_name::<M>(),
+                err
+            );
+            *failed = true;
+        }
+    }
+}
+
+/// A material prepared for drawing: its uniform data and textures in a descriptor set of its
+/// `RafxMaterialPass`
+pub struct GpuMaterial {
+    pub descriptor_set: DescriptorSetArc,
+}
+
+impl GpuMaterial {
+    pub fn new<M: RafxMaterial>(
+        render_resources: &RenderResources,
+        rafx_material_pass: &RafxMaterialPass<M>,
+        gpu_textures: &GpuTextures,
+        material: &M,
+    ) -> RafxResult<GpuMaterial> {
+        // The transparent pass has the same descriptor set layouts, the depth prepass has none
+        // for the material
+        let material_pass_resource = rafx_material_pass.material_passes.opaque.get_raw();
+        let descriptor_set_layout = material_pass_resource
+            .descriptor_set_layouts
+            .get(MATERIAL_DESCRIPTOR_SET_INDEX)
+            .ok_or_else(|| {
+                RafxError::StringError(format!(
+                    "The material pass of {} has no descriptor set {}",
+                    std::any::type_name::<M>(),
+                    MATERIAL_DESCRIPTOR_SET_INDEX
+                ))
+            })?;
+
+        let textures = material.textures();
+        if textures.len() != M::TEXTURE_BINDINGS.len() {
+            return Err(RafxError::StringError(format!(
+                "{} has {} textures but {} texture bindings",
+                std::any::type_name::<M>(),
+                textures.len(),
+                M::TEXTURE_BINDINGS.len()
+            )));
+        }
+
+        let mut descriptor_set_allocator = render_resources
+            .resource_manager
+            .create_descriptor_set_allocator();
+        let mut descriptor_set = descriptor_set_allocator
+            .create_dyn_descriptor_set_uninitialized(descriptor_set_layout)?;
+
+        if std::mem::size_of::<M::Uniform>() > 0 {
+            descriptor_set.set_buffer_data(MATERIAL_UNIFORM_BINDING, &material.uniform());
+        }
+        for (texture, binding) in textures.iter().zip(M::TEXTURE_BINDINGS) {
+            let gpu_texture = gpu_textures.get_or_white(texture.as_ref()).ok_or_else(|| {
+                RafxError::StringError("The white texture isn't uploaded".to_string())
+            })?;
+            descriptor_set.set_image(binding.texture, &gpu_texture.image_view);
+            descriptor_set.set_sampler(binding.sampler, &gpu_texture.sampler);
+        }
+        descriptor_set.flush(&mut descriptor_set_allocator)?;
+
+        Ok(GpuMaterial {
+            descriptor_set: descriptor_set.descriptor_set().clone(),
+        })
+    }
+}
+
+/// The `GpuMaterial` of every prepared `M` asset
+pub struct GpuMaterials<M: RafxMaterial> {
+    gpu_materials: HashMap<HandleId, GpuMaterial>,
+    marker: PhantomData<fn() -> M>,
+}
+
+impl<M: RafxMaterial> Default for GpuMaterials<M> {
+    fn default() -> Self {
+        GpuMaterials {
+            gpu_materials: HashMap::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: RafxMaterial> GpuMaterials<M> {
+    pub fn get(&self, material: &Handle<M>) -> Option<&GpuMaterial> {
+        self.gpu_materials.get(&material.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.gpu_materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gpu_materials.is_empty()
+    }
+
+    fn prepare(
+        &mut self,
+        render_resources: &RenderResources,
+        rafx_material_pass: &RafxMaterialPass<M>,
+        gpu_textures: &GpuTextures,
+        material_id: HandleId,
+        material: &M,
+    ) {
+        match GpuMaterial::new(render_resources, rafx_material_pass, gpu_textures, material) {
+            Ok(gpu_material) => {
+                self.gpu_materials.insert(material_id, gpu_material);
+            }
+            Err(err) => {
+                error!("Failed to prepare material {:?}: {:?}", material_id, err);
+                self.gpu_materials.remove(&material_id);
+            }
+        }
+    }
+}
+
+/// Prepares created and modified `M` assets and releases removed ones. Materials that were
+/// loaded before the `RafxMaterialPass` and the white texture existed are prepared once they do,
+/// materials are prepared again when one of their textures finished uploading or was removed.
+fn prepare_materials<M: RafxMaterial>(
+    mut material_events: EventReader<AssetEvent<M>>,
+    mut texture_events: EventReader<AssetEvent<Texture>>,
+    mut prepared_existing_materials: Local<bool>,
+    render_resources: Option<Res<RenderResources>>,
+    rafx_material_pass: Option<Res<RafxMaterialPass<M>>>,
+    materials: Res<Assets<M>>,
+    gpu_textures: Res<GpuTextures>,
+    mut gpu_materials: ResMut<GpuMaterials<M>>,
+) {
+    let (render_resources, rafx_material_pass) = match (render_resources, rafx_material_pass) {
+        (Some(render_resources), Some(rafx_material_pass)) => {
+            (render_resources, rafx_material_pass)
+        }
+        _ => return,
+    };
+    // Textures are bound when materials are prepared, so at least the white one has to be there
+    if !gpu_textures.has_white() {
+        return;
+    }
+
+    if !*prepared_existing_materials {
+        for (material_id, material) in materials.iter() {
+            gpu_materials.prepare(
+                &render_resources,
+                &rafx_material_pass,
+                &gpu_textures,
+                material_id,
+                material,
+            );
+        }
+        *prepared_existing_materials = true;
+
+        // Pending events are about the materials that were just prepared as they are now
+        material_events.iter().for_each(drop);
+        texture_events.iter().for_each(drop);
+        return;
+    }
+
+    let mut changed_materials = HashSet::new();
+    for event in material_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_materials.insert(handle.id);
+            }
+            AssetEvent::Removed { handle } => {
+                changed_materials.remove(&handle.id);
+                gpu_materials.gpu_materials.remove(&handle.id);
+            }
+        }
+    }
+
+    // Created and modified textures can be bound once their upload is complete
+    let changed_textures = texture_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Removed { handle } => Some(handle.id),
+            AssetEvent::Created { .. } | AssetEvent::Modified { .. } => None,
+        })
+        .chain(gpu_textures.uploaded().iter().copied())
+        .collect::<HashSet<_>>();
+    if !changed_textures.is_empty() && !M::TEXTURE_BINDINGS.is_empty() {
+        for (material_id, material) in materials.iter() {
+            let uses_changed_texture = material
+                .textures()
+                .iter()
+                .flatten()
+                .any(|texture| changed_textures.contains(&texture.id));
+            if uses_changed_texture {
+                changed_materials.insert(material_id);
+            }
+        }
+    }
+
+    for material_id in changed_materials {
+        if let Some(material) = materials.get(material_id) {
+            gpu_materials.prepare(
+                &render_resources,
+                &rafx_material_pass,
+                &gpu_textures,
+                material_id,
+                material,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Color;
+    use bevy_rafx_plugin::{load_cooked_shader_package, test_util};
+    use rafx::framework::ReflectedEntryPoint;
+
+    #[test]
+    fn material_uniform() {
+        let uniform = MaterialUniform::from(&StandardMaterial {
+            base_color: Color::rgba_linear(0.5, 0.25, 1.0, 0.5),
+            metallic: 1.0,
+            unlit: true,
+            ..Default::default()
+        });
+
+        assert_eq!(uniform.base_color, [0.5, 0.25, 1.0, 0.5]);
+        assert_eq!(uniform.emissive, [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(uniform.metallic, 1.0);
+        assert_eq!(uniform.unlit, 1);
+        // Laid out like the std140 MaterialData block
+        assert_eq!(std::mem::size_of::<MaterialUniform>(), 48);
+    }
+
+    fn cooked_entry_point(shader_package: &str) -> ReflectedEntryPoint {
+        test_util::set_workspace_dir();
+        load_cooked_shader_package(shader_package)
+            .unwrap()
+            .find_entry_point("main")
+            .cloned()
+            .unwrap()
+    }
+
+    /// Fails when the raw shaders changed without cooking them again
+    #[test]
+    fn standard_material_shaders_are_cooked() {
+        let vert = cooked_entry_point(StandardMaterial::SHADER_PACKAGES[0]);
+        let frag = cooked_entry_point(StandardMaterial::SHADER_PACKAGES[1]);
+
+        let mut vertex_inputs = vert
+            .vertex_inputs
+            .iter()
+            .map(|vertex_input| (vertex_input.semantic.as_str(), vertex_input.location))
+            .collect::<Vec<_>>();
+        vertex_inputs.sort_by_key(|&(_, location)| location);
+        assert_eq!(
+            vertex_inputs,
+            vec![("POSITION", 0), ("TEXCOORD", 1), ("COLOR", 2)]
+        );
+
+        for &descriptor_set_index in &[
+            PER_VIEW_DESCRIPTOR_SET_INDEX,
+            PER_OBJECT_DESCRIPTOR_SET_INDEX,
+        ] {
+            let descriptor_set_layout = vert
+                .descriptor_set_layouts
+                .get(descriptor_set_index)
+                .and_then(Option::as_ref)
+                .unwrap_or_else(|| {
+                    panic!("shader.vert has no descriptor set {}", descriptor_set_index)
+                });
+            assert!(descriptor_set_layout.bindings.iter().any(|binding| {
+                binding.resource.binding == 0
+                    && binding.internal_buffer_per_descriptor_size.is_some()
+            }));
+        }
+
+        let material_descriptor_set_layout = frag
+            .descriptor_set_layouts
+            .get(MATERIAL_DESCRIPTOR_SET_INDEX)
+            .and_then(Option::as_ref)
+            .expect("shader.frag has no material descriptor set");
+        let mut bindings = material_descriptor_set_layout
+            .bindings
+            .iter()
+            .map(|binding| binding.resource.binding)
+            .collect::<Vec<_>>();
+        bindings.sort_unstable();
+        let mut expected_bindings = StandardMaterial::TEXTURE_BINDINGS
+            .iter()
+            .flat_map(|binding| vec![binding.texture, binding.sampler])
+            .collect::<Vec<_>>();
+        expected_bindings.push(MATERIAL_UNIFORM_BINDING);
+        expected_bindings.sort_unstable();
+        assert_eq!(bindings, expected_bindings);
+    }
+
+    #[test]
+    fn only_the_depth_prepass_writes_depth() {
+        let depth_prepass = StandardMaterial::fixed_function_state(MaterialPassKind::DepthPrepass);
+        let opaque = StandardMaterial::fixed_function_state(MaterialPassKind::Opaque);
+
+        assert!(depth_prepass.depth_state.depth_write_enable);
+        assert!(depth_prepass
+            .blend_state
+            .render_target_blend_states
+            .iter()
+            .all(|render_target_blend_state| render_target_blend_state.masks.is_empty()));
+        assert!(!opaque.depth_state.depth_write_enable);
+        assert_eq!(
+            opaque.depth_state.depth_compare_op,
+            RafxCompareOp::LessOrEqual
+        );
+        assert_eq!(
+            StandardMaterial::depth_prepass_shader_packages(),
+            &["shader.vert.cookedshaderpackage"]
+        );
+    }
+
+    #[test]
+    fn transparent_pass_blends_without_writing_depth() {
+        let transparent = StandardMaterial::fixed_function_state(MaterialPassKind::Transparent);
+
+        assert!(!transparent.depth_state.depth_write_enable);
+        assert!(transparent.depth_state.depth_test_enable);
+        assert_eq!(
+            format!("{:?}", transparent.blend_state),
+            format!("{:?}", RafxBlendState::default_alpha_enabled())
+        );
+    }
+
+    #[test]
+    fn standard_material_textures() {
+        let base_color_texture = Handle::<Texture>::weak(HandleId::random::<Texture>());
+        let emissive_texture = Handle::<Texture>::weak(HandleId::random::<Texture>());
+        let material = StandardMaterial {
+            base_color_texture: Some(base_color_texture.clone()),
+            emissive_texture: Some(emissive_texture.clone()),
+            ..Default::default()
+        };
+
+        let textures = material.textures();
+        assert_eq!(textures.len(), StandardMaterial::TEXTURE_BINDINGS.len());
+        assert_eq!(
+            textures,
+            vec![
+                Some(base_color_texture),
+                None,
+                None,
+                None,
+                Some(emissive_texture)
+            ]
+        );
+    }
+}
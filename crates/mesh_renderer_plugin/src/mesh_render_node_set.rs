@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use bevy::prelude::{Entity, Handle, Query, RemovedComponents, ResMut, With};
+use bevy_rafx_plugin::VisibilityComponent;
+use rafx::nodes::{
+    GenericRenderNodeHandle, RenderFeature, RenderFeatureIndex, RenderNodeCount, RenderNodeIndex,
+    RenderNodeSet,
+};
+
+use crate::{Mesh, MeshRenderFeature};
+
+/// The mesh entity a render node draws. Everything else is read from the entity during extract.
+pub struct MeshRenderNode {
+    pub entity: Entity,
+}
+
+/// A render node of the `MeshRenderNodeSet`. The generation tells it apart from earlier nodes that
+/// had the same index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshRenderNodeHandle {
+    index: RenderNodeIndex,
+    generation: u32,
+}
+
+impl MeshRenderNodeHandle {
+    pub fn index(&self) -> RenderNodeIndex {
+        self.index
+    }
+
+    pub fn as_raw_generic_handle(&self) -> GenericRenderNodeHandle {
+        GenericRenderNodeHandle::new(
+            <MeshRenderFeature as RenderFeature>::feature_index(),
+            self.index,
+        )
+    }
+}
+
+impl From<MeshRenderNodeHandle> for GenericRenderNodeHandle {
+    fn from(handle: MeshRenderNodeHandle) -> Self {
+        handle.as_raw_generic_handle()
+    }
+}
+
+#[derive(Default)]
+struct MeshRenderNodeSlot {
+    generation: u32,
+    node: Option<MeshRenderNode>,
+}
+
+/// Render nodes of the `MeshRenderFeature`, which are attached to the visibility objects of mesh
+/// entities.
+///
+/// Frame nodes only carry the index of their render node. So a freed index isn't reused until
+/// `update` is called in the next frame, once no frame packet can refer to the old node anymore,
+/// and looking up a freed index finds nothing instead of another entity's node.
+#[derive(Default)]
+pub struct MeshRenderNodeSet {
+    slots: Vec<MeshRenderNodeSlot>,
+    free_indices: Vec<RenderNodeIndex>,
+    freed_this_frame: Vec<RenderNodeIndex>,
+}
+
+impl MeshRenderNodeSet {
+    pub fn register_mesh(&mut self, node: MeshRenderNode) -> MeshRenderNodeHandle {
+        let index = match self.free_indices.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(MeshRenderNodeSlot::default());
+                (self.slots.len() - 1) as RenderNodeIndex
+            }
+        };
+
+        let slot = &mut self.slots[index as usize];
+        slot.node = Some(node);
+        MeshRenderNodeHandle {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    /// The node at the index of a frame node, None if it was freed
+    pub fn get(&self, render_node_index: RenderNodeIndex) -> Option<&MeshRenderNode> {
+        self.slots.get(render_node_index as usize)?.node.as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: &MeshRenderNodeHandle) -> Option<&mut MeshRenderNode> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.node.as_mut()
+    }
+
+    /// Frees the node, unless it was already freed
+    pub fn free(&mut self, handle: &MeshRenderNodeHandle) {
+        let slot = match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation && slot.node.is_some() => slot,
+            _ => return,
+        };
+
+        slot.node = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.freed_this_frame.push(handle.index);
+    }
+
+    /// Makes the indices of nodes freed since the last call available again
+    pub fn update(&mut self) {
+        self.free_indices.append(&mut self.freed_this_frame);
+    }
+}
+
+impl RenderNodeSet for MeshRenderNodeSet {
+    fn feature_index(&self) -> RenderFeatureIndex {
+        <MeshRenderFeature as RenderFeature>::feature_index()
+    }
+
+    fn max_render_node_count(&self) -> RenderNodeCount {
+        self.slots.len() as RenderNodeCount
+    }
+}
+
+/// The render node of every mesh entity. Entities keep their node when their visibility object
+/// is replaced, so it can be attached to the new object.
+#[derive(Default)]
+pub(crate) struct MeshRenderNodeHandles(HashMap<Entity, MeshRenderNodeHandle>);
+
+impl MeshRenderNodeHandles {
+    /// Returns the entity's render node, allocating it on first use
+    pub(crate) fn get_or_register(
+        &mut self,
+        entity: Entity,
+        mesh_render_nodes: &mut MeshRenderNodeSet,
+    ) -> &MeshRenderNodeHandle {
+        self.0
+            .entry(entity)
+            .or_insert_with(|| mesh_render_nodes.register_mesh(MeshRenderNode { entity }))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains(&self, entity: Entity) -> bool {
+        self.0.contains_key(&entity)
+    }
+}
+
+/// Drops the render nodes of entities that lost their mesh or their `VisibilityComponent`, or
+/// were despawned
+pub(crate) fn release_mesh_render_nodes(
+    removed_meshes: RemovedComponents<Handle<Mesh>>,
+    removed_visibility_components: RemovedComponents<VisibilityComponent>,
+    mut mesh_render_node_handles: ResMut<MeshRenderNodeHandles>,
+    mut mesh_render_nodes: ResMut<MeshRenderNodeSet>,
+    query: Query<(), (With<Handle<Mesh>>, With<VisibilityComponent>)>,
+) {
+    // Nodes freed in the last frame can't be in a frame packet anymore
+    mesh_render_nodes.update();
+
+    for entity in removed_meshes
+        .iter()
+        .chain(removed_visibility_components.iter())
+    {
+        // The component may have been removed and inserted again in the same frame
+        if query.get(entity).is_err() {
+            if let Some(handle) = mesh_render_node_handles.0.remove(&entity) {
+                mesh_render_nodes.free(&handle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn freed_nodes_are_reused_in_the_next_frame() {
+        let mut mesh_render_nodes = MeshRenderNodeSet::default();
+        let entity = Entity::new(0);
+        let other_entity = Entity::new(1);
+
+        let handle = mesh_render_nodes.register_mesh(MeshRenderNode { entity });
+        mesh_render_nodes.free(&handle);
+        assert!(mesh_render_nodes.get(handle.index()).is_none());
+
+        // Still in use by this frame's frame packet
+        let other_handle = mesh_render_nodes.register_mesh(MeshRenderNode {
+            entity: other_entity,
+        });
+        assert_ne!(other_handle.index(), handle.index());
+
+        mesh_render_nodes.update();
+        let reused_handle = mesh_render_nodes.register_mesh(MeshRenderNode { entity });
+        assert_eq!(reused_handle.index(), handle.index());
+
+        // The old handle doesn't refer to the new node, and freeing it again does nothing
+        assert!(mesh_render_nodes.get_mut(&handle).is_none());
+        mesh_render_nodes.free(&handle);
+        assert_eq!(
+            mesh_render_nodes
+                .get(reused_handle.index())
+                .map(|mesh_render_node| mesh_render_node.entity),
+            Some(entity)
+        );
+        assert_eq!(mesh_render_nodes.max_render_node_count(), 2);
+    }
+}
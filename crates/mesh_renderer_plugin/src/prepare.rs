@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use bevy::{
+    log::error,
+    math::Mat4,
+    prelude::{Res, ResMut},
+};
+use bevy_rafx_plugin::{
+    phases::{
+        depth_prepass_render_phase::DepthPrepassRenderPhase,
+        opaque_render_phase::OpaqueRenderPhase, transparent_render_phase::TransparentRenderPhase,
+    },
+    FramePacketViews, PreparedRenderFeature, PreparedRenderFeatures, RenderResources,
+};
+use rafx::{
+    api::{RafxError, RafxResult},
+    framework::{DescriptorSetAllocator, DescriptorSetArc, MaterialPassResource, ResourceArc},
+    nodes::{RenderPhase, RenderPhaseIndex, RenderView, SubmitNode, ViewSubmitNodes},
+};
+
+use crate::{
+    write::MeshCommandWriter, ExtractedMeshes, GpuMesh, GpuMeshes, MaterialPasses,
+    PER_OBJECT_DESCRIPTOR_SET_INDEX, PER_VIEW_DESCRIPTOR_SET_INDEX,
+};
+
+/// The uniform data of a view, laid out like `PerViewData` in `shader.vert`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PerViewUniform {
+    pub view_proj: [[f32; 4]; 4],
+}
+
+impl From<&RenderView> for PerViewUniform {
+    fn from(view: &RenderView) -> Self {
+        PerViewUniform {
+            view_proj: view.view_proj().to_cols_array_2d(),
+        }
+    }
+}
+
+/// The uniform data of a mesh, laid out like `PerObjectData` in `shader.vert`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PerObjectUniform {
+    pub model: [[f32; 4]; 4],
+}
+
+impl From<Mat4> for PerObjectUniform {
+    fn from(model: Mat4) -> Self {
+        PerObjectUniform {
+            model: model.to_cols_array_2d(),
+        }
+    }
+}
+
+/// An extracted mesh that is uploaded and has a material pass, so it can be drawn
+#[derive(Clone)]
+pub struct PreparedMesh {
+    pub gpu_mesh: GpuMesh,
+    pub material_passes: MaterialPasses,
+    /// The mesh's `PerObjectUniform`
+    pub per_object_descriptor_set: DescriptorSetArc,
+    /// The material's uniform and textures, None until the material is prepared
+    pub material_descriptor_set: Option<DescriptorSetArc>,
+}
+
+/// Submit nodes of a view, sorted by the phases
+#[derive(Default)]
+pub struct PreparedMeshView {
+    pub depth_prepass: Vec<SubmitNode>,
+    pub opaque: Vec<SubmitNode>,
+    pub transparent: Vec<SubmitNode>,
+    /// The view's `PerViewUniform`, None if no mesh can be drawn
+    pub per_view_descriptor_set: Option<DescriptorSetArc>,
+}
+
+impl PreparedMeshView {
+    fn new(
+        view_submit_nodes: &ViewSubmitNodes,
+        per_view_descriptor_set: Option<DescriptorSetArc>,
+    ) -> Self {
+        PreparedMeshView {
+            depth_prepass: sorted_submit_nodes::<DepthPrepassRenderPhase>(view_submit_nodes),
+            opaque: sorted_submit_nodes::<OpaqueRenderPhase>(view_submit_nodes),
+            transparent: sorted_submit_nodes::<TransparentRenderPhase>(view_submit_nodes),
+            per_view_descriptor_set,
+        }
+    }
+
+    /// Empty for phases the mesh feature doesn't draw in
+    pub fn submit_nodes(&self, render_phase_index: RenderPhaseIndex) -> &[SubmitNode] {
+        if render_phase_index == DepthPrepassRenderPhase::render_phase_index() {
+            &self.depth_prepass
+        } else if render_phase_index == OpaqueRenderPhase::render_phase_index() {
+            &self.opaque
+        } else if render_phase_index == TransparentRenderPhase::render_phase_index() {
+            &self.transparent
+        } else {
+            &[]
+        }
+    }
+}
+
+fn sorted_submit_nodes<P: RenderPhase>(view_submit_nodes: &ViewSubmitNodes) -> Vec<SubmitNode> {
+    P::sort_submit_nodes(
+        view_submit_nodes
+            .submit_nodes(P::render_phase_index())
+            .to_vec(),
+    )
+}
+
+/// The `ExtractedMeshes` of the current frame, ready to be written by a `MeshCommandWriter`
+#[derive(Default)]
+pub struct PreparedMeshes {
+    /// Indexed by frame node, like `ExtractedMeshes::frame_nodes`. None for meshes that can't be
+    /// drawn (yet).
+    pub frame_nodes: Vec<Option<PreparedMesh>>,
+    /// Views of `FramePacketViews`, in the same order
+    pub views: Vec<PreparedMeshView>,
+}
+
+impl PreparedMeshes {
+    /// Draws the meshes of the views of `FramePacketViews`, which must be the views the meshes
+    /// were prepared for
+    pub fn command_writer(&self, frame_packet_views: &FramePacketViews) -> MeshCommandWriter {
+        let per_view_descriptor_sets = frame_packet_views
+            .0
+            .iter()
+            .zip(&self.views)
+            .filter_map(|(view, prepared_mesh_view)| {
+                Some((
+                    view.view_index(),
+                    prepared_mesh_view.per_view_descriptor_set.clone()?,
+                ))
+            })
+            .collect();
+        MeshCommandWriter::new(self.frame_nodes.clone(), per_view_descriptor_sets)
+    }
+
+    /// The command writer and submit nodes the `Renderer` draws the meshes with
+    pub fn prepared_render_feature(
+        &self,
+        frame_packet_views: &FramePacketViews,
+    ) -> PreparedRenderFeature {
+        PreparedRenderFeature {
+            command_writer: Box::new(self.command_writer(frame_packet_views)),
+            view_submit_nodes: self
+                .views
+                .iter()
+                .map(|prepared_mesh_view| {
+                    let mut submit_nodes = HashMap::default();
+                    submit_nodes.insert(
+                        DepthPrepassRenderPhase::render_phase_index(),
+                        prepared_mesh_view.depth_prepass.clone(),
+                    );
+                    submit_nodes.insert(
+                        OpaqueRenderPhase::render_phase_index(),
+                        prepared_mesh_view.opaque.clone(),
+                    );
+                    submit_nodes.insert(
+                        TransparentRenderPhase::render_phase_index(),
+                        prepared_mesh_view.transparent.clone(),
+                    );
+                    submit_nodes
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Creates a descriptor set with the uniform data at binding 0, in the material pass's layout of
+/// the descriptor set
+fn create_uniform_descriptor_set<T: Copy + 'static>(
+    descriptor_set_allocator: &mut DescriptorSetAllocator,
+    material_pass: &ResourceArc<MaterialPassResource>,
+    descriptor_set_index: usize,
+    data: &T,
+) -> RafxResult<DescriptorSetArc> {
+    let descriptor_set_layout = material_pass
+        .get_raw()
+        .descriptor_set_layouts
+        .get(descriptor_set_index)
+        .ok_or_else(|| {
+            RafxError::StringError(format!(
+                "The material pass has no descriptor set {}",
+                descriptor_set_index
+            ))
+        })?
+        .clone();
+
+    let mut descriptor_set =
+        descriptor_set_allocator.create_dyn_descriptor_set_uninitialized(&descriptor_set_layout)?;
+    descriptor_set.set_buffer_data(0, data);
+    descriptor_set.flush(descriptor_set_allocator)?;
+    Ok(descriptor_set.descriptor_set().clone())
+}
+
+/// Looks up the GPU resources of the extracted meshes, creates the descriptor sets of their
+/// transforms and views and sorts their submit nodes. Hands the meshes to the `Renderer` through
+/// the `PreparedRenderFeatures`.
+pub(crate) fn mesh_prepare(
+    render_resources: Option<Res<RenderResources>>,
+    frame_packet_views: Res<FramePacketViews>,
+    extracted_meshes: Res<ExtractedMeshes>,
+    gpu_meshes: Res<GpuMeshes>,
+    mut prepared_meshes: ResMut<PreparedMeshes>,
+    mut prepared_render_features: ResMut<PreparedRenderFeatures>,
+) {
+    // Without a device there are no GpuMeshes either, so no mesh can be drawn
+    let mut descriptor_set_allocator = render_resources.as_ref().map(|render_resources| {
+        render_resources
+            .resource_manager
+            .create_descriptor_set_allocator()
+    });
+
+    prepared_meshes.frame_nodes = extracted_meshes
+        .frame_nodes
+        .iter()
+        .map(|extracted_mesh| {
+            let extracted_mesh = extracted_mesh.as_ref()?;
+            let material = extracted_mesh.material.as_ref()?;
+            let gpu_mesh = gpu_meshes.get(&extracted_mesh.mesh)?.clone();
+            let material_passes = material.material_passes.clone()?;
+
+            let per_object_descriptor_set = create_uniform_descriptor_set(
+                descriptor_set_allocator.as_mut()?,
+                &material_passes.opaque,
+                PER_OBJECT_DESCRIPTOR_SET_INDEX,
+                &PerObjectUniform::from(extracted_mesh.transform),
+            )
+            .map_err(|err| {
+                error!(
+                    "Failed to prepare mesh of entity {:?}: {:?}",
+                    extracted_mesh.entity, err
+                )
+            })
+            .ok()?;
+
+            Some(PreparedMesh {
+                gpu_mesh,
+                material_passes,
+                per_object_descriptor_set,
+                material_descriptor_set: material.descriptor_set.clone(),
+            })
+        })
+        .collect();
+
+    // Every mesh shader has the layout of shader.vert's PerViewData, so the view's descriptor set
+    // can be bound for any of them
+    let per_view_material_pass = prepared_meshes
+        .frame_nodes
+        .iter()
+        .flatten()
+        .next()
+        .map(|prepared_mesh| prepared_mesh.material_passes.opaque.clone());
+
+    prepared_meshes.views = extracted_meshes
+        .view_submit_nodes
+        .iter()
+        .zip(&frame_packet_views.0)
+        .map(|(view_submit_nodes, view)| {
+            let per_view_descriptor_set =
+                per_view_material_pass.as_ref().and_then(|material_pass| {
+                    create_uniform_descriptor_set(
+                        descriptor_set_allocator.as_mut()?,
+                        material_pass,
+                        PER_VIEW_DESCRIPTOR_SET_INDEX,
+                        &PerViewUniform::from(view),
+                    )
+                    .map_err(|err| {
+                        error!("Failed to prepare view {}: {:?}", view.debug_name(), err)
+                    })
+                    .ok()
+                });
+            PreparedMeshView::new(view_submit_nodes, per_view_descriptor_set)
+        })
+        .collect();
+
+    prepared_render_features
+        .0
+        .push(prepared_meshes.prepared_render_feature(&frame_packet_views));
+}
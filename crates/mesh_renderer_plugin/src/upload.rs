@@ -0,0 +1,276 @@
+use bevy::{log::error, prelude::ResMut};
+use bevy_rafx_plugin::RenderResources;
+use rafx::api::{
+    RafxBuffer, RafxBufferBarrier, RafxBufferDef, RafxCmdCopyBufferToBufferParams,
+    RafxCmdCopyBufferToTextureParams, RafxCommandBuffer, RafxCommandBufferDef, RafxCommandPool,
+    RafxCommandPoolDef, RafxDeviceContext, RafxError, RafxFence, RafxFenceStatus, RafxMemoryUsage,
+    RafxQueue, RafxQueueType, RafxResourceState, RafxResourceType, RafxResult, RafxTexture,
+    RafxTextureBarrier, RafxTextureDef,
+};
+
+/// Offsets of mip levels in the staging buffer are aligned to this, which satisfies every backend
+const STAGING_BUFFER_TEXTURE_ALIGNMENT: usize = 256;
+
+/// Identifies the upload a resource was enqueued in, see `GpuUploads::is_complete`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UploadId(u64);
+
+/// The copies enqueued during one frame, and the staging buffers they copy from
+struct Upload {
+    id: UploadId,
+    // Dropped in declaration order, the pool goes after its command buffer
+    command_buffer: RafxCommandBuffer,
+    _command_pool: RafxCommandPool,
+    fence: RafxFence,
+    staging_buffers: Vec<RafxBuffer>,
+}
+
+struct UploadQueue {
+    device_context: RafxDeviceContext,
+    queue: RafxQueue,
+    recording: Option<Upload>,
+    in_flight: Vec<Upload>,
+    next_id: u64,
+}
+
+impl UploadQueue {
+    fn recording(&mut self) -> RafxResult<&mut Upload> {
+        if self.recording.is_none() {
+            let mut command_pool = self
+                .queue
+                .create_command_pool(&RafxCommandPoolDef { transient: true })?;
+            let command_buffer = command_pool.create_command_buffer(&RafxCommandBufferDef {
+                is_secondary: false,
+            })?;
+            command_buffer.begin()?;
+
+            self.recording = Some(Upload {
+                id: UploadId(self.next_id),
+                command_buffer,
+                _command_pool: command_pool,
+                fence: self.device_context.create_fence()?,
+                staging_buffers: Vec::new(),
+            });
+            self.next_id += 1;
+        }
+
+        Ok(self.recording.as_mut().unwrap())
+    }
+}
+
+/// Copies resources into device local memory on the GPU. Everything enqueued during a frame is
+/// recorded into one command buffer that is submitted at the end of `RenderStage::PreExtract`.
+/// Submitted uploads are polled every frame instead of waited for, so whoever enqueued a resource
+/// holds on to it until `is_complete` says its upload finished.
+#[derive(Default)]
+pub struct GpuUploads {
+    // Created on first use, once there are RenderResources
+    upload_queue: Option<UploadQueue>,
+}
+
+impl GpuUploads {
+    fn upload_queue(&mut self, render_resources: &RenderResources) -> RafxResult<&mut UploadQueue> {
+        if self.upload_queue.is_none() {
+            let device_context = render_resources.device_context.clone();
+            self.upload_queue = Some(UploadQueue {
+                queue: device_context.create_queue(RafxQueueType::Graphics)?,
+                device_context,
+                recording: None,
+                in_flight: Vec::new(),
+                next_id: 0,
+            });
+        }
+
+        Ok(self.upload_queue.as_mut().unwrap())
+    }
+
+    /// Creates a device local buffer and enqueues copying the data into it. The buffer is in
+    /// `resource_state` once the upload is complete.
+    pub fn upload_buffer<T: Copy>(
+        &mut self,
+        render_resources: &RenderResources,
+        data: &[T],
+        resource_type: RafxResourceType,
+        resource_state: RafxResourceState,
+    ) -> RafxResult<(UploadId, RafxBuffer)> {
+        let upload_queue = self.upload_queue(render_resources)?;
+        let device_context = upload_queue.device_context.clone();
+        let size = std::mem::size_of_val(data) as u64;
+
+        let staging_buffer = device_context.create_buffer(
+            &RafxBufferDef::for_staging_buffer_data(data, RafxResourceType::BUFFER),
+        )?;
+        staging_buffer.copy_to_host_visible_buffer(data)?;
+
+        let buffer = device_context.create_buffer(&RafxBufferDef {
+            size,
+            memory_usage: RafxMemoryUsage::GpuOnly,
+            resource_type,
+            ..Default::default()
+        })?;
+
+        let upload = upload_queue.recording()?;
+        upload.command_buffer.cmd_copy_buffer_to_buffer(
+            &staging_buffer,
+            &buffer,
+            &RafxCmdCopyBufferToBufferParams {
+                src_byte_offset: 0,
+                dst_byte_offset: 0,
+                size,
+            },
+        )?;
+        upload.command_buffer.cmd_resource_barrier(
+            &[RafxBufferBarrier::state_transition(
+                &buffer,
+                RafxResourceState::COPY_DST,
+                resource_state,
+            )],
+            &[],
+        )?;
+        upload.staging_buffers.push(staging_buffer);
+
+        Ok((upload.id, buffer))
+    }
+
+    /// Creates a texture and enqueues copying the given mip levels into it, largest first. The
+    /// texture is a `SHADER_RESOURCE` once the upload is complete.
+    pub fn upload_texture(
+        &mut self,
+        render_resources: &RenderResources,
+        texture_def: &RafxTextureDef,
+        mips: &[Vec<u8>],
+    ) -> RafxResult<(UploadId, RafxTexture)> {
+        if mips.len() as u32 != texture_def.mip_count {
+            return Err(RafxError::StringError(format!(
+                "{} mip levels were given for a texture with {}",
+                mips.len(),
+                texture_def.mip_count
+            )));
+        }
+
+        let upload_queue = self.upload_queue(render_resources)?;
+        let device_context = upload_queue.device_context.clone();
+
+        let mut staging_data = Vec::new();
+        let mut mip_offsets = Vec::with_capacity(mips.len());
+        for mip in mips {
+            let aligned_len = (staging_data.len() + STAGING_BUFFER_TEXTURE_ALIGNMENT - 1)
+                / STAGING_BUFFER_TEXTURE_ALIGNMENT
+                * STAGING_BUFFER_TEXTURE_ALIGNMENT;
+            staging_data.resize(aligned_len, 0u8);
+            mip_offsets.push(staging_data.len() as u64);
+            staging_data.extend_from_slice(mip);
+        }
+
+        let staging_buffer = device_context.create_buffer(
+            &RafxBufferDef::for_staging_buffer_data(&staging_data, RafxResourceType::BUFFER),
+        )?;
+        staging_buffer.copy_to_host_visible_buffer(&staging_data)?;
+
+        let texture = device_context.create_texture(texture_def)?;
+
+        let upload = upload_queue.recording()?;
+        upload.command_buffer.cmd_resource_barrier(
+            &[],
+            &[RafxTextureBarrier::state_transition(
+                &texture,
+                RafxResourceState::UNDEFINED,
+                RafxResourceState::COPY_DST,
+            )],
+        )?;
+        for (mip_level, &buffer_offset) in mip_offsets.iter().enumerate() {
+            upload.command_buffer.cmd_copy_buffer_to_texture(
+                &staging_buffer,
+                &texture,
+                &RafxCmdCopyBufferToTextureParams {
+                    buffer_offset,
+                    array_layer: 0,
+                    mip_level: mip_level as u8,
+                },
+            )?;
+        }
+        upload.command_buffer.cmd_resource_barrier(
+            &[],
+            &[RafxTextureBarrier::state_transition(
+                &texture,
+                RafxResourceState::COPY_DST,
+                RafxResourceState::SHADER_RESOURCE,
+            )],
+        )?;
+        upload.staging_buffers.push(staging_buffer);
+
+        Ok((upload.id, texture))
+    }
+
+    /// Whether the upload finished, so the resources enqueued in it can be used
+    pub fn is_complete(&self, upload_id: UploadId) -> bool {
+        let upload_queue = match &self.upload_queue {
+            Some(upload_queue) => upload_queue,
+            None => return false,
+        };
+
+        let is_recording = upload_queue
+            .recording
+            .as_ref()
+            .map_or(false, |upload| upload.id == upload_id);
+        let is_in_flight = upload_queue
+            .in_flight
+            .iter()
+            .any(|upload| upload.id == upload_id);
+        upload_id.0 < upload_queue.next_id && !is_recording && !is_in_flight
+    }
+
+    /// Releases the staging buffers of the uploads that finished
+    fn poll(&mut self) -> RafxResult<()> {
+        let upload_queue = match &mut self.upload_queue {
+            Some(upload_queue) => upload_queue,
+            None => return Ok(()),
+        };
+
+        let mut result = Ok(());
+        upload_queue
+            .in_flight
+            .retain(|upload| match upload.fence.get_fence_status() {
+                Ok(RafxFenceStatus::Complete) => false,
+                Ok(_) => true,
+                Err(err) => {
+                    result = Err(err);
+                    true
+                }
+            });
+        result
+    }
+
+    /// Submits everything enqueued this frame
+    fn submit(&mut self) -> RafxResult<()> {
+        let upload_queue = match &mut self.upload_queue {
+            Some(upload_queue) => upload_queue,
+            None => return Ok(()),
+        };
+        let upload = match upload_queue.recording.take() {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+
+        upload.command_buffer.end()?;
+        upload_queue
+            .queue
+            .submit(&[&upload.command_buffer], &[], &[], Some(&upload.fence))?;
+        upload_queue.in_flight.push(upload);
+        Ok(())
+    }
+}
+
+/// Finds the uploads that finished since the last frame, at the start of `RenderStage::PreExtract`
+pub(crate) fn poll_gpu_uploads(mut gpu_uploads: ResMut<GpuUploads>) {
+    if let Err(err) = gpu_uploads.poll() {
+        error!("Failed to poll GPU uploads: {:?}", err);
+    }
+}
+
+/// Submits the uploads of this frame, at the end of `RenderStage::PreExtract`
+pub(crate) fn submit_gpu_uploads(mut gpu_uploads: ResMut<GpuUploads>) {
+    if let Err(err) = gpu_uploads.submit() {
+        error!("Failed to submit GPU uploads: {:?}", err);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use rafx::{
+    api::{RafxIndexBufferBinding, RafxResult, RafxVertexBufferBinding},
+    framework::DescriptorSetArc,
+    nodes::{
+        FeatureCommandWriter, RenderFeature, RenderFeatureIndex, RenderJobWriteContext,
+        RenderPhaseIndex, RenderView, RenderViewIndex, SubmitNodeId,
+    },
+};
+
+use crate::{prepare::PreparedMesh, MaterialPassKind, MeshRenderFeature, MESH_VERTEX_LAYOUT};
+
+/// Draws the prepared meshes of a frame. The submit node ids of `PreparedMeshView`s are the
+/// frame node indices the writer is called with.
+pub struct MeshCommandWriter {
+    frame_nodes: Vec<Option<PreparedMesh>>,
+    per_view_descriptor_sets: HashMap<RenderViewIndex, DescriptorSetArc>,
+}
+
+impl MeshCommandWriter {
+    pub(crate) fn new(
+        frame_nodes: Vec<Option<PreparedMesh>>,
+        per_view_descriptor_sets: HashMap<RenderViewIndex, DescriptorSetArc>,
+    ) -> Self {
+        MeshCommandWriter {
+            frame_nodes,
+            per_view_descriptor_sets,
+        }
+    }
+}
+
+impl FeatureCommandWriter<RenderJobWriteContext> for MeshCommandWriter {
+    fn render_element(
+        &self,
+        write_context: &mut RenderJobWriteContext,
+        view: &RenderView,
+        render_phase_index: RenderPhaseIndex,
+        index: SubmitNodeId,
+    ) -> RafxResult<()> {
+        // Not uploaded yet, or without a material pass
+        let prepared_mesh = match self.frame_nodes.get(index as usize) {
+            Some(Some(prepared_mesh)) => prepared_mesh,
+            _ => return Ok(()),
+        };
+        let per_view_descriptor_set = match self.per_view_descriptor_sets.get(&view.view_index()) {
+            Some(per_view_descriptor_set) => per_view_descriptor_set,
+            None => return Ok(()),
+        };
+        let material_pass_kind = match MaterialPassKind::from_render_phase_index(render_phase_index)
+        {
+            Some(material_pass_kind) => material_pass_kind,
+            None => return Ok(()),
+        };
+        let gpu_mesh = &prepared_mesh.gpu_mesh;
+
+        let pipeline = write_context
+            .resource_context
+            .graphics_pipeline_cache()
+            .get_or_create_graphics_pipeline(
+                render_phase_index,
+                prepared_mesh.material_passes.get(material_pass_kind),
+                &write_context.render_target_meta,
+                &MESH_VERTEX_LAYOUT,
+            )?;
+
+        let command_buffer = &write_context.command_buffer;
+        command_buffer.cmd_bind_pipeline(&*pipeline.get_raw().pipeline)?;
+        per_view_descriptor_set.bind(command_buffer)?;
+        prepared_mesh
+            .per_object_descriptor_set
+            .bind(command_buffer)?;
+        // The depth prepass doesn't shade, so its pipeline has no material descriptor set
+        if material_pass_kind != MaterialPassKind::DepthPrepass {
+            if let Some(material_descriptor_set) = &prepared_mesh.material_descriptor_set {
+                material_descriptor_set.bind(command_buffer)?;
+            }
+        }
+        command_buffer.cmd_bind_vertex_buffers(
+            0,
+            &[RafxVertexBufferBinding {
+                buffer: &*gpu_mesh.vertex_buffer.get_raw().buffer,
+                byte_offset: 0,
+            }],
+        )?;
+        command_buffer.cmd_bind_index_buffer(&RafxIndexBufferBinding {
+            buffer: &*gpu_mesh.index_buffer.get_raw().buffer,
+            byte_offset: 0,
+            index_type: gpu_mesh.index_type,
+        })?;
+        command_buffer.cmd_draw_indexed(gpu_mesh.index_count, 0, 0)
+    }
+
+    fn feature_debug_name(&self) -> &'static str {
+        MeshRenderFeature::feature_debug_name()
+    }
+
+    fn feature_index(&self) -> RenderFeatureIndex {
+        MeshRenderFeature::feature_index()
+    }
+}
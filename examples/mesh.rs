@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_rafx_gltf::GltfPlugin;
-use bevy_rafx_plugin::{BevyRafxPlugin, PerspectiveCameraBundle, RenderFeatureMaskBuilder};
-use mesh_renderer_plugin::{MeshRenderFeature, MeshRendererPlugin};
+use bevy_rafx_plugin::{BevyRafxPlugin, RafxCameraBundle};
+use mesh_renderer_plugin::MeshRendererPlugin;
 
 fn main() {
     let mut app = App::build();
@@ -22,13 +22,7 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     let handle = asset_server.load("models/Monkey.gltf#Scene0");
     commands.spawn_scene(handle);
 
-    let render_feature_mask = RenderFeatureMaskBuilder::default()
-        .add_render_feature::<MeshRenderFeature>()
-        .build();
-
-    commands
-        .spawn_bundle(PerspectiveCameraBundle::new_3d())
-        .insert(render_feature_mask);
+    commands.spawn_bundle(RafxCameraBundle::new_3d());
 }
 
 #[cfg(feature = "print_schedule")]